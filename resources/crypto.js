@@ -0,0 +1,144 @@
+// End-to-end encrypted pastes. The key only ever exists in the browser and in
+// the fragment of the link, which browsers never send to the server.
+// Stored files are [1 byte version][12 byte iv][AES-256-GCM ciphertext], and
+// the plaintext is [2 byte little endian filename length][filename][content]
+const SFSS_CRYPTO_VERSION = 1;
+const SFSS_IV_LENGTH = 12;
+
+function sfssToBase64url(bytes) {
+	let binary = "";
+	for (const b of bytes) {
+		binary += String.fromCharCode(b);
+	}
+	return btoa(binary).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+function sfssFromBase64url(text) {
+	const binary = atob(text.replace(/-/g, "+").replace(/_/g, "/"));
+	return Uint8Array.from(binary, c => c.charCodeAt(0));
+}
+
+async function sfssEncrypt(filename, content) {
+	const name = new TextEncoder().encode(filename);
+	const plaintext = new Uint8Array(2 + name.length + content.length);
+	new DataView(plaintext.buffer).setUint16(0, name.length, true);
+	plaintext.set(name, 2);
+	plaintext.set(content, 2 + name.length);
+
+	const rawKey = crypto.getRandomValues(new Uint8Array(32));
+	const iv = crypto.getRandomValues(new Uint8Array(SFSS_IV_LENGTH));
+	const key = await crypto.subtle.importKey("raw", rawKey, "AES-GCM", false, ["encrypt"]);
+	const ciphertext = new Uint8Array(await crypto.subtle.encrypt({ name: "AES-GCM", iv }, key, plaintext));
+
+	const data = new Uint8Array(1 + SFSS_IV_LENGTH + ciphertext.length);
+	data[0] = SFSS_CRYPTO_VERSION;
+	data.set(iv, 1);
+	data.set(ciphertext, 1 + SFSS_IV_LENGTH);
+	return { key: sfssToBase64url(rawKey), data };
+}
+
+async function sfssDecrypt(encodedKey, data) {
+	if (data[0] !== SFSS_CRYPTO_VERSION) {
+		throw new Error("Unsupported encryption version " + data[0]);
+	}
+	const iv = data.slice(1, 1 + SFSS_IV_LENGTH);
+	const key = await crypto.subtle.importKey("raw", sfssFromBase64url(encodedKey), "AES-GCM", false, ["decrypt"]);
+	const plaintext = new Uint8Array(await crypto.subtle.decrypt({ name: "AES-GCM", iv }, key, data.slice(1 + SFSS_IV_LENGTH)));
+	const nameLength = new DataView(plaintext.buffer).getUint16(0, true);
+	return {
+		filename: new TextDecoder().decode(plaintext.slice(2, 2 + nameLength)),
+		content: plaintext.slice(2 + nameLength),
+	};
+}
+
+// Encrypts the text or the first file of the upload form, and uploads only the ciphertext
+async function sfssUploadEncrypted(form) {
+	const fileInput = document.getElementById("file");
+	const text = document.getElementById("textFile").value;
+	let filename = "untitled.txt";
+	let content = new TextEncoder().encode(text);
+	if (fileInput.files.length > 1) {
+		alert("Only a single file can be encrypted");
+		return;
+	}
+	if (fileInput.files.length === 1) {
+		filename = fileInput.files[0].name;
+		content = new Uint8Array(await fileInput.files[0].arrayBuffer());
+	}
+	const { key, data } = await sfssEncrypt(filename, content);
+
+	const body = new FormData(form);
+	body.delete("file");
+	body.append("file", new Blob([data], { type: "application/octet-stream" }), "encrypted");
+	const resp = await fetch(form.action + "/api?json", { method: "POST", body });
+	if (!resp.ok) {
+		alert("Upload failed: " + resp.status);
+		return;
+	}
+	const uploaded = await resp.json();
+	const root = form.action.replace(/\/upload$/, "");
+	let link = root + "/" + uploaded.hash;
+	if (uploaded.password) {
+		link += "?password=" + encodeURIComponent(uploaded.password);
+	}
+	link += "#" + key;
+
+	const result = document.createElement("a");
+	result.href = link;
+	result.textContent = link;
+	const note = document.createElement("p");
+	note.textContent = "The key is part of the link, without it the file can't be decrypted by anyone.";
+	document.body.replaceChildren(result, note);
+}
+
+// Fetches and decrypts the file on the encrypted viewer page
+async function sfssShowDecrypted(container) {
+	const status = document.getElementById("status");
+	const key = location.hash.slice(1);
+	if (!key) {
+		status.textContent = "The link is missing the key needed to decrypt this file.";
+		return;
+	}
+	try {
+		const resp = await fetch(container.dataset.raw, { cache: "no-store" });
+		if (!resp.ok) {
+			throw new Error("Could not load the file: " + resp.status);
+		}
+		const { filename, content } = await sfssDecrypt(key, new Uint8Array(await resp.arrayBuffer()));
+		document.getElementById("filename").textContent = filename;
+
+		const url = URL.createObjectURL(new Blob([content]));
+		const download = document.getElementById("download");
+		download.href = url;
+		download.download = filename;
+		document.getElementById("links").hidden = false;
+
+		const text = new TextDecoder("utf-8", { fatal: true });
+		try {
+			const pre = document.createElement("pre");
+			pre.textContent = text.decode(content);
+			container.appendChild(pre);
+		} catch (e) {
+			// Not text, only offer the download
+		}
+		status.remove();
+	} catch (e) {
+		status.textContent = "Decryption failed, the key may be wrong. " + e.message;
+	}
+}
+
+document.addEventListener("DOMContentLoaded", () => {
+	const form = document.getElementById("upload");
+	if (form) {
+		form.addEventListener("submit", ev => {
+			if (document.getElementById("encrypted").checked) {
+				ev.preventDefault();
+				sfssUploadEncrypted(form);
+			}
+		});
+	}
+	const container = document.getElementById("decrypted");
+	if (container) {
+		sfssShowDecrypted(container);
+	}
+});
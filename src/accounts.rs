@@ -0,0 +1,294 @@
+use std::io::Result as IoResult;
+
+use rocket::http::{Cookie, SameSite, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::content::Html;
+use rocket::response::Redirect;
+use serde::{Deserialize, Serialize};
+
+use crate::encryption;
+use crate::sfss_format::SfssFile;
+use crate::utils::{read_json, unix_time, write_json};
+
+// Local user accounts, stored in SFSS_LOCATION/users.json with PBKDF2 hashes
+// of their passwords and managed with sfss user. Logging in sets a session
+// cookie sealed like unlock cookies, uploads made while logged in are owned by
+// the user and show up on their dashboard
+const FILE_NAME: &str = "users.json";
+const COOKIE: &str = "sfss_session";
+const SESSION_LIFETIME: u64 = 7 * 24 * 60 * 60;
+const PASSWORD_ROUNDS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+// How many days the extend button on the dashboard adds to an expiry
+pub const EXTEND_DAYS: u64 = 30;
+
+// The body of the login form
+#[derive(FromForm)]
+pub struct LoginForm {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct User {
+    name: String,
+    // [4 byte rounds][16 byte salt][32 byte hash] encoded as base64, empty for
+    // users that can only log in through single sign-on
+    #[serde(default)]
+    password: String,
+    // The subject of the identity at the OpenID Connect provider
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    oidc_subject: Option<String>,
+}
+
+// Pages that redirect to the login page without a session
+#[derive(Responder)]
+pub enum Page {
+    Html(Html<String>),
+    Redirect(Redirect),
+    Status(Status),
+}
+
+fn load() -> IoResult<Vec<User>> {
+    read_json(FILE_NAME)
+}
+
+fn save(users: &[User]) -> IoResult<()> {
+    write_json(FILE_NAME, &users)
+}
+
+fn derive(password: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+    encryption::derive_key(password, salt, rounds)
+}
+
+fn hash_password(password: &str) -> String {
+    let salt: [u8; SALT_LEN] = rand::random();
+    let mut res = PASSWORD_ROUNDS.to_le_bytes().to_vec();
+    res.extend_from_slice(&salt);
+    res.extend_from_slice(&derive(password, &salt, PASSWORD_ROUNDS));
+    base64::encode(&res)
+}
+
+fn verify_password(stored: &str, password: &str) -> bool {
+    let stored = match base64::decode(stored) {
+        Ok(stored) if stored.len() == 4 + SALT_LEN + 32 => stored,
+        _ => return false,
+    };
+    let rounds = u32::from_le_bytes([stored[0], stored[1], stored[2], stored[3]]);
+    let hash = derive(password, &stored[4..4 + SALT_LEN], rounds);
+    // Compares every byte, so the time taken doesn't tell how much matched
+    hash.iter()
+        .zip(&stored[4 + SALT_LEN..])
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+// What is stored as the owner of uploads by the user
+pub fn owner(username: &str) -> String {
+    format!("user:{}", username)
+}
+
+// Checks the username and password, unknown users take as long as wrong passwords
+pub fn verify(username: &str, password: &str) -> bool {
+    let users = match load() {
+        Ok(users) => users,
+        Err(e) => {
+            eprintln!("Error reading users: {:?}", e);
+            return false;
+        }
+    };
+    match users.iter().find(|u| u.name == username) {
+        Some(user) => verify_password(&user.password, password),
+        None => {
+            derive(password, &[0; SALT_LEN], PASSWORD_ROUNDS);
+            false
+        }
+    }
+}
+
+// The user of an identity from single sign-on, which is created on the first
+// login. Never takes over an existing user that has the same name
+pub fn oidc_user(subject: &str, username: &str) -> Result<String, Status> {
+    let mut users = load().map_err(|e| {
+        eprintln!("Error reading users: {:?}", e);
+        Status::InternalServerError
+    })?;
+    if let Some(user) = users
+        .iter()
+        .find(|u| u.oidc_subject.as_deref() == Some(subject))
+    {
+        return Ok(user.name.clone());
+    }
+    if username.is_empty() || username.contains(|c: char| c.is_control()) {
+        return Err(Status::BadRequest);
+    }
+    if users.iter().any(|u| u.name == username) {
+        eprintln!(
+            "Refused single sign-on of {}, there already is another user with that name",
+            username
+        );
+        return Err(Status::Conflict);
+    }
+    users.push(User {
+        name: username.to_string(),
+        password: String::new(),
+        oidc_subject: Some(subject.to_string()),
+    });
+    save(&users).map_err(|e| {
+        eprintln!("Error writing users: {:?}", e);
+        Status::InternalServerError
+    })?;
+    eprintln!("Created user {} on their first single sign-on", username);
+    Ok(username.to_string())
+}
+
+fn cookie_path() -> String {
+    format!("{}/", crate::APP_CONTEXT.webroot)
+}
+
+pub fn session_cookie(username: &str) -> IoResult<Cookie<'static>> {
+    let mut plaintext = (unix_time() + SESSION_LIFETIME).to_le_bytes().to_vec();
+    plaintext.extend_from_slice(username.as_bytes());
+    let sealed = encryption::seal_token(COOKIE.as_bytes(), &plaintext)?;
+    Ok(Cookie::build(
+        COOKIE,
+        base64::encode_config(&sealed, base64::URL_SAFE_NO_PAD),
+    )
+    .path(cookie_path())
+    .http_only(true)
+    .secure(crate::APP_CONTEXT.url.starts_with("https://"))
+    .same_site(SameSite::Strict)
+    .finish())
+}
+
+pub fn removed_session_cookie() -> Cookie<'static> {
+    Cookie::build(COOKIE, "").path(cookie_path()).finish()
+}
+
+// The logged in user, sessions of removed users end right away
+pub fn session_user(req: &Request<'_>) -> Option<String> {
+    let cookie = req.cookies().get(COOKIE)?;
+    let sealed = base64::decode_config(cookie.value(), base64::URL_SAFE_NO_PAD).ok()?;
+    let plaintext = encryption::open_token(COOKIE.as_bytes(), &sealed).ok()?;
+    if plaintext.len() < 8 {
+        return None;
+    }
+    let mut expires = [0; 8];
+    expires.copy_from_slice(&plaintext[..8]);
+    if u64::from_le_bytes(expires) < unix_time() {
+        return None;
+    }
+    let username = String::from_utf8(plaintext[8..].to_vec()).ok()?;
+    load()
+        .ok()?
+        .iter()
+        .any(|u| u.name == username)
+        .then(|| username)
+}
+
+pub struct Session {
+    pub username: String,
+}
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for Session {
+    type Error = ();
+
+    async fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        match session_user(req) {
+            Some(username) => Outcome::Success(Session { username }),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+// Every stored file owned by the user with its size, newest first. This reads
+// the header of every file, which is fine for the amount of files sfss is meant for
+pub fn owned_files(username: &str) -> IoResult<Vec<(SfssFile, u64)>> {
+    let owner = Some(owner(username));
+    let mut res = Vec::new();
+    for entry in std::fs::read_dir(std::env::var("SFSS_LOCATION").unwrap())? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // Thumbnails, temporary files and the like have an extension, stored files never do
+        if name.contains('.') {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        match SfssFile::new(name, true) {
+            Ok(file) if file.owner == owner => {
+                res.push((file, metadata.len(), metadata.modified().ok()))
+            }
+            _ => (),
+        }
+    }
+    res.sort_by(|a, b| b.2.cmp(&a.2));
+    Ok(res
+        .into_iter()
+        .map(|(file, size, _)| (file, size))
+        .collect())
+}
+
+const USAGE: &str = "usage: sfss user add NAME
+       sfss user password NAME
+       sfss user list
+       sfss user remove NAME
+The password is read from stdin";
+
+fn read_password() -> Result<String, String> {
+    eprint!("Password: ");
+    let mut password = String::new();
+    std::io::stdin()
+        .read_line(&mut password)
+        .map_err(|e| e.to_string())?;
+    let password = password.trim_end_matches(|c: char| c == '\n' || c == '\r');
+    crate::password::check(password)?;
+    Ok(password.to_string())
+}
+
+// sfss user, for managing accounts from the command line
+pub fn command(args: &[String]) -> Result<(), String> {
+    let mut users = load().map_err(|e| format!("Error reading {}: {}", FILE_NAME, e))?;
+    let name = args.get(1);
+    match (args.get(0).map(String::as_str), name) {
+        (Some("add"), Some(name)) => {
+            if users.iter().any(|u| &u.name == name) {
+                return Err(format!("There already is a user named {}", name));
+            }
+            if name.is_empty() || name.contains(|c: char| c.is_control()) {
+                return Err("Invalid username".into());
+            }
+            users.push(User {
+                name: name.clone(),
+                password: hash_password(&read_password()?),
+                oidc_subject: None,
+            });
+        }
+        (Some("password"), Some(name)) => {
+            let password = read_password()?;
+            let user = users
+                .iter_mut()
+                .find(|u| &u.name == name)
+                .ok_or_else(|| format!("There is no user named {}", name))?;
+            user.password = hash_password(&password);
+        }
+        (Some("remove"), Some(name)) => {
+            let count = users.len();
+            users.retain(|u| &u.name != name);
+            if users.len() == count {
+                return Err(format!("There is no user named {}", name));
+            }
+        }
+        (Some("list"), _) => {
+            for user in users {
+                match user.oidc_subject {
+                    Some(_) => println!("{}\tsingle sign-on", user.name),
+                    None => println!("{}", user.name),
+                }
+            }
+            return Ok(());
+        }
+        _ => return Err(USAGE.into()),
+    }
+    save(&users).map_err(|e| format!("Error writing {}: {}", FILE_NAME, e))
+}
@@ -0,0 +1,187 @@
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+
+use rocket::Request;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::utils::{read_json, write_json};
+
+// API keys for uploading, sent as Authorization: Bearer KEY. Keys are stored
+// in SFSS_LOCATION/apikeys.json, only as their sha256 hash, and are managed
+// with sfss api-key, which prints the key itself once when it is added.
+// Uploads with a key are owned by it, and use its settings
+const FILE_NAME: &str = "apikeys.json";
+const PREFIX: &str = "sfss_";
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ApiKey {
+    pub name: String,
+    hash: String,
+    // Flags every upload with the key gets, on top of the ones it asks for
+    #[serde(default)]
+    pub public: bool,
+    #[serde(default)]
+    pub protected: bool,
+    #[serde(default)]
+    pub no_preview: bool,
+    // Largest size of a single file in bytes
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    // Mime types that may be uploaded, like image/png or image/*, empty allows all
+    #[serde(default)]
+    pub allowed_types: Vec<String>,
+    // Storage quota of the key, instead of SFSS_QUOTA_BYTES and SFSS_QUOTA_FILES
+    #[serde(default)]
+    pub quota_bytes: Option<u64>,
+    #[serde(default)]
+    pub quota_files: Option<u64>,
+}
+
+impl ApiKey {
+    // What is stored as the owner of uploads with this key
+    pub fn owner(&self) -> String {
+        format!("key:{}", self.name)
+    }
+
+    pub fn allows_type(&self, mime: &str) -> bool {
+        self.allowed_types.is_empty()
+            || self.allowed_types.iter().any(|allowed| {
+                allowed == mime
+                    || (allowed.ends_with("/*") && mime.starts_with(&allowed[..allowed.len() - 1]))
+            })
+    }
+}
+
+fn hash(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn load() -> IoResult<Vec<ApiKey>> {
+    read_json(FILE_NAME)
+}
+
+fn save(keys: &[ApiKey]) -> IoResult<()> {
+    write_json(FILE_NAME, &keys)
+}
+
+// Whether uploads without an API key are allowed, SFSS_ANONYMOUS_UPLOADS
+pub fn anonymous_uploads() -> bool {
+    std::env::var("SFSS_ANONYMOUS_UPLOADS").map_or(true, |v| v != "false" && v != "0")
+}
+
+// The key of the request. Err if a key was sent that doesn't exist, so a typo
+// doesn't quietly upload anonymously
+pub fn from_request(req: &Request<'_>) -> IoResult<Option<ApiKey>> {
+    let key = match req
+        .headers()
+        .get_one("Authorization")
+        .and_then(|auth| auth.strip_prefix("Bearer "))
+    {
+        Some(key) => key.trim(),
+        None => return Ok(None),
+    };
+    let hash = hash(key);
+    load()?
+        .into_iter()
+        .find(|k| k.hash == hash)
+        .map(Some)
+        .ok_or_else(|| IoError::new(IoErrorKind::PermissionDenied, "unknown API key"))
+}
+
+const USAGE: &str = "usage: sfss api-key add NAME [--public] [--protected] [--no-preview] [--max-size BYTES] [--quota-bytes SIZE] [--quota-files COUNT] [--allow MIME]...
+       sfss api-key list
+       sfss api-key remove NAME";
+
+// sfss api-key, for managing keys from the command line
+pub fn command(args: &[String]) -> Result<(), String> {
+    let mut keys = load().map_err(|e| format!("Error reading {}: {}", FILE_NAME, e))?;
+    match args.get(0).map(String::as_str) {
+        Some("add") => {
+            let mut key = ApiKey {
+                name: args.get(1).cloned().ok_or(USAGE)?,
+                ..ApiKey::default()
+            };
+            if keys.iter().any(|k| k.name == key.name) {
+                return Err(format!("There already is a key named {}", key.name));
+            }
+            let mut options = args[2..].iter();
+            while let Some(option) = options.next() {
+                match option.as_str() {
+                    "--public" => key.public = true,
+                    "--protected" => key.protected = true,
+                    "--no-preview" => key.no_preview = true,
+                    "--max-size" => {
+                        let size = options.next().and_then(|s| s.parse().ok());
+                        key.max_size = Some(size.ok_or("--max-size needs a size in bytes")?);
+                    }
+                    "--quota-bytes" => {
+                        let size = options.next().and_then(|s| crate::quota::parse_size(s));
+                        key.quota_bytes = Some(size.ok_or("--quota-bytes needs a size")?);
+                    }
+                    "--quota-files" => {
+                        let files = options.next().and_then(|s| s.parse().ok());
+                        key.quota_files =
+                            Some(files.ok_or("--quota-files needs an amount of files")?);
+                    }
+                    "--allow" => key
+                        .allowed_types
+                        .push(options.next().cloned().ok_or("--allow needs a mime type")?),
+                    _ => return Err(USAGE.into()),
+                }
+            }
+            let secret = format!(
+                "{}{}",
+                PREFIX,
+                base64::encode_config(&rand::random::<[u8; 32]>(), base64::URL_SAFE_NO_PAD)
+            );
+            key.hash = hash(&secret);
+            keys.push(key);
+            save(&keys).map_err(|e| format!("Error writing {}: {}", FILE_NAME, e))?;
+            println!("{}", secret);
+            Ok(())
+        }
+        Some("list") => {
+            for key in keys {
+                let mut settings = Vec::new();
+                if key.public {
+                    settings.push("public".to_string());
+                }
+                if key.protected {
+                    settings.push("protected".to_string());
+                }
+                if key.no_preview {
+                    settings.push("no preview".to_string());
+                }
+                if let Some(max_size) = key.max_size {
+                    settings.push(format!("max {}", crate::utils::human_size(max_size)));
+                }
+                if let Some(quota_bytes) = key.quota_bytes {
+                    settings.push(format!("quota {}", crate::utils::human_size(quota_bytes)));
+                }
+                if let Some(quota_files) = key.quota_files {
+                    settings.push(format!("quota {} files", quota_files));
+                }
+                if false == key.allowed_types.is_empty() {
+                    settings.push(key.allowed_types.join(" "));
+                }
+                println!("{}\t{}", key.name, settings.join(", "));
+            }
+            Ok(())
+        }
+        Some("remove") => {
+            let name = args.get(1).ok_or(USAGE)?;
+            let count = keys.len();
+            keys.retain(|k| &k.name != name);
+            if keys.len() == count {
+                return Err(format!("There is no key named {}", name));
+            }
+            save(&keys).map_err(|e| format!("Error writing {}: {}", FILE_NAME, e))
+        }
+        _ => Err(USAGE.into()),
+    }
+}
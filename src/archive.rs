@@ -0,0 +1,241 @@
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+use std::io::{Cursor, Read};
+
+use flate2::read::GzDecoder;
+use serde::Serialize;
+
+use crate::sfss_format::SfssFile;
+use crate::utils::percent_encode;
+
+// Archives are read straight from memory, entries larger than this are
+// refused so a small upload can't decompress into gigabytes
+const MAX_ENTRY_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+#[derive(Serialize)]
+pub struct ArchiveEntry {
+    pub path: String,
+    // Percent encoded path for linking to the entry
+    pub url: String,
+    pub size: u64,
+    pub dir: bool,
+}
+
+pub fn kind(file: &SfssFile) -> Option<ArchiveKind> {
+    match file.mime.as_deref()? {
+        "application/zip" => Some(ArchiveKind::Zip),
+        "application/x-tar" => Some(ArchiveKind::Tar),
+        // Only gzipped tarballs can be browsed, not any gzipped file
+        "application/gzip" => {
+            let mut header = [0; 262];
+            GzDecoder::new(file.content())
+                .read_exact(&mut header)
+                .ok()
+                .filter(|_| &header[257..262] == b"ustar")
+                .map(|_| ArchiveKind::TarGz)
+        }
+        _ => None,
+    }
+}
+
+// Tarballs often have every path start with ./
+fn normalize(path: &str) -> &str {
+    path.trim_start_matches("./")
+}
+
+fn entry_url(path: &str) -> String {
+    path.split('/')
+        .map(percent_encode)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn tar_entries<R: Read>(reader: R) -> IoResult<Vec<ArchiveEntry>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = normalize(&entry.path()?.to_string_lossy()).to_string();
+        entries.push(ArchiveEntry {
+            url: entry_url(&path),
+            size: entry.header().size()?,
+            dir: entry.header().entry_type().is_dir(),
+            path,
+        });
+    }
+    Ok(entries)
+}
+
+pub fn list(kind: ArchiveKind, content: &[u8]) -> IoResult<Vec<ArchiveEntry>> {
+    match kind {
+        ArchiveKind::Zip => {
+            let mut archive = zip::ZipArchive::new(Cursor::new(content)).map_err(zip_error)?;
+            let mut entries = Vec::with_capacity(archive.len());
+            for i in 0..archive.len() {
+                let entry = archive.by_index(i).map_err(zip_error)?;
+                entries.push(ArchiveEntry {
+                    path: entry.name().to_string(),
+                    url: entry_url(entry.name()),
+                    size: entry.size(),
+                    dir: entry.is_dir(),
+                });
+            }
+            Ok(entries)
+        }
+        ArchiveKind::Tar => tar_entries(content),
+        ArchiveKind::TarGz => tar_entries(GzDecoder::new(content)),
+    }
+}
+
+fn read_limited<R: Read>(reader: R, size: u64) -> IoResult<Vec<u8>> {
+    if size > MAX_ENTRY_SIZE {
+        return Err(IoError::new(
+            IoErrorKind::InvalidData,
+            "archive entry too large",
+        ));
+    }
+    let mut buf = Vec::with_capacity(size as usize);
+    reader.take(MAX_ENTRY_SIZE).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+fn tar_entry<R: Read>(reader: R, path: &str) -> IoResult<Option<Vec<u8>>> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if entry.header().entry_type().is_file()
+            && normalize(&entry.path()?.to_string_lossy()) == path
+        {
+            let size = entry.header().size()?;
+            return read_limited(entry, size).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+// Reads a single file out of the archive, None if there is no such file
+pub fn read_entry(kind: ArchiveKind, content: &[u8], path: &str) -> IoResult<Option<Vec<u8>>> {
+    match kind {
+        ArchiveKind::Zip => {
+            let mut archive = zip::ZipArchive::new(Cursor::new(content)).map_err(zip_error)?;
+            let entry = match archive.by_name(path) {
+                Ok(entry) if entry.is_file() => entry,
+                Ok(_) | Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+                Err(e) => return Err(zip_error(e)),
+            };
+            let size = entry.size();
+            read_limited(entry, size).map(Some)
+        }
+        ArchiveKind::Tar => tar_entry(content, path),
+        ArchiveKind::TarGz => tar_entry(GzDecoder::new(content), path),
+    }
+}
+
+fn zip_error(e: zip::result::ZipError) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    // Names are written as they are, tar::Builder would drop the leading ./
+    fn tar(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            if name.ends_with('/') {
+                header.set_entry_type(tar::EntryType::Directory);
+            }
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            let options = zip::write::FileOptions::default();
+            if name.ends_with('/') {
+                writer.add_directory(*name, options).unwrap();
+            } else {
+                writer.start_file(*name, options).unwrap();
+                writer.write_all(data).unwrap();
+            }
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn summary(entries: &[ArchiveEntry]) -> Vec<(&str, &str, u64, bool)> {
+        entries
+            .iter()
+            .map(|e| (e.path.trim_end_matches('/'), e.url.as_str(), e.size, e.dir))
+            .collect()
+    }
+
+    const ENTRIES: &[(&str, &[u8])] = &[("docs/", b""), ("docs/read me.txt", b"hello")];
+
+    #[test]
+    fn list_zip() {
+        let entries = list(ArchiveKind::Zip, &zip(ENTRIES)).unwrap();
+        assert_eq!(
+            summary(&entries),
+            vec![
+                ("docs", "docs/", 0, true),
+                ("docs/read me.txt", "docs/read%20me.txt", 5, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn list_tar() {
+        let content = tar(&[("./docs/", b""), ("./docs/read me.txt", b"hello")]);
+        let expected = vec![
+            ("docs", "docs/", 0, true),
+            ("docs/read me.txt", "docs/read%20me.txt", 5, false),
+        ];
+        assert_eq!(
+            summary(&list(ArchiveKind::Tar, &content).unwrap()),
+            expected
+        );
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&content).unwrap();
+        let content = gz.finish().unwrap();
+        assert_eq!(
+            summary(&list(ArchiveKind::TarGz, &content).unwrap()),
+            expected
+        );
+    }
+
+    #[test]
+    fn read_entries() {
+        let zip = zip(ENTRIES);
+        let tar = tar(&[("./docs/", b""), ("./docs/read me.txt", b"hello")]);
+        for (kind, content) in &[(ArchiveKind::Zip, zip), (ArchiveKind::Tar, tar)] {
+            let read = |path| read_entry(*kind, content, path).unwrap();
+            assert_eq!(read("docs/read me.txt"), Some(b"hello".to_vec()));
+            // Directories and missing files can't be read
+            assert_eq!(read("docs/"), None);
+            assert_eq!(read("docs/other.txt"), None);
+        }
+    }
+
+    #[test]
+    fn invalid_archives() {
+        assert!(list(ArchiveKind::Zip, b"not a zip").is_err());
+        assert!(read_limited(&b""[..], MAX_ENTRY_SIZE + 1).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::http::Status;
+
+use crate::sfss_format::SfssFile;
+
+// Brute-force protection for passwords of protected files and accounts. Failed
+// attempts are counted per client and file or account, after a few free ones
+// every further failure doubles how long that client is locked out of it.
+// Failures are also counted per file or account, but only to log an alert when
+// it is being guessed, a correct password or unlock cookie is never refused
+// because of what other clients did. Requests without a client address, like
+// over a unix socket, all count as the same client
+const CLIENT_FREE_ATTEMPTS: u32 = 5;
+const TARGET_ALERT_AFTER: u32 = 20;
+const FIRST_LOCKOUT: Duration = Duration::from_secs(2);
+const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+// Failures are forgotten after this long without new ones
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+// The most entries kept, once full the oldest tenth is dropped at once so
+// making room doesn't go through every entry for each failure
+const MAX_ENTRIES: usize = 10_000;
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+struct Tracker<K> {
+    free_attempts: u32,
+    max_lockout: Duration,
+    failures: HashMap<K, Failures>,
+}
+
+impl<K: Hash + Eq + Clone> Tracker<K> {
+    fn new(free_attempts: u32, max_lockout: Duration) -> Self {
+        Tracker {
+            free_attempts,
+            max_lockout,
+            failures: HashMap::new(),
+        }
+    }
+
+    fn is_locked(&self, key: &K, now: Instant) -> bool {
+        self.locked_for(key, now).is_some()
+    }
+
+    // How long the key is still locked out for
+    fn locked_for(&self, key: &K, now: Instant) -> Option<Duration> {
+        self.failures
+            .get(key)
+            .and_then(|f| f.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    // Returns how long the key is locked out for, if this failure locked it
+    fn fail(&mut self, key: K, now: Instant) -> Option<Duration> {
+        if self.failures.len() >= MAX_ENTRIES && false == self.failures.contains_key(&key) {
+            self.make_room(now);
+        }
+        let failures = self.failures.entry(key).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        if now.duration_since(failures.last) >= FORGET_AFTER {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = now;
+        if failures.count <= self.free_attempts {
+            return None;
+        }
+        let doublings = (failures.count - self.free_attempts - 1).min(31);
+        let lockout = FIRST_LOCKOUT
+            .checked_mul(1 << doublings)
+            .map_or(self.max_lockout, |lockout| lockout.min(self.max_lockout));
+        failures.locked_until = Some(now + lockout);
+        Some(lockout)
+    }
+
+    fn make_room(&mut self, now: Instant) {
+        self.failures
+            .retain(|_, f| now.duration_since(f.last) < FORGET_AFTER);
+        let keep = MAX_ENTRIES - MAX_ENTRIES / 10;
+        if self.failures.len() > keep {
+            let mut oldest: Vec<(Instant, K)> = self
+                .failures
+                .iter()
+                .map(|(key, f)| (f.last, key.clone()))
+                .collect();
+            oldest.sort_unstable_by_key(|(last, _)| *last);
+            for (_, key) in oldest.iter().take(oldest.len() - keep) {
+                self.failures.remove(key);
+            }
+        }
+    }
+
+    fn failures(&self, key: &K) -> u32 {
+        self.failures.get(key).map_or(0, |f| f.count)
+    }
+
+    fn forget(&mut self, key: &K) {
+        self.failures.remove(key);
+    }
+}
+
+struct Attempts {
+    // Targets are files by their code, and accounts as user:NAME
+    clients: Tracker<(Option<IpAddr>, String)>,
+    targets: Tracker<String>,
+}
+
+lazy_static::lazy_static! {
+    static ref ATTEMPTS: Mutex<Attempts> = Mutex::new(Attempts {
+        clients: Tracker::new(CLIENT_FREE_ATTEMPTS, MAX_LOCKOUT),
+        targets: Tracker::new(TARGET_ALERT_AFTER, MAX_LOCKOUT),
+    });
+}
+
+// Checks a password for the target, like a file or an account. While the
+// client is locked out of the target or out of password attempts the password
+// isn't even checked, and 429 is returned instead of 403
+pub fn attempt(
+    target: &str,
+    ip: Option<IpAddr>,
+    check: impl FnOnce() -> bool,
+) -> Result<(), Status> {
+    let now = Instant::now();
+    let client = (ip, target.to_string());
+    {
+        let attempts = ATTEMPTS.lock().unwrap_or_else(|e| e.into_inner());
+        if attempts.clients.is_locked(&client, now)
+            || ip.and_then(crate::ratelimit::password_wait).is_some()
+        {
+            return Err(Status::TooManyRequests);
+        }
+    }
+    // Checking the password is slow on purpose, so the lock isn't held for it
+    let correct = check();
+    let mut attempts = ATTEMPTS.lock().unwrap_or_else(|e| e.into_inner());
+    if correct {
+        // The failures of the target are kept, someone knowing the password
+        // doesn't mean nobody else is guessing it
+        attempts.clients.forget(&client);
+        return Ok(());
+    }
+    if let Some(ip) = ip {
+        crate::ratelimit::password_failed(ip);
+    }
+    if let Some(lockout) = attempts.clients.fail(client, now) {
+        eprintln!(
+            "Client {} is locked out of {} for {}s after failed password attempts",
+            client_name(ip),
+            target,
+            lockout.as_secs()
+        );
+    }
+    let target = target.to_string();
+    if attempts.targets.fail(target.clone(), now).is_some() {
+        // Logged at 21, 32, 64 and so on, not for every failure
+        let failures = attempts.targets.failures(&target);
+        if failures == TARGET_ALERT_AFTER + 1 || failures.is_power_of_two() {
+            eprintln!(
+                "{} might be the target of password guessing, {} failed attempts, last from {}",
+                target,
+                failures,
+                client_name(ip)
+            );
+        }
+    }
+    Err(Status::Forbidden)
+}
+
+fn client_name(ip: Option<IpAddr>) -> String {
+    ip.map_or("without an address".into(), |ip| ip.to_string())
+}
+
+// How long the client is still locked out for, for the Retry-After header. The
+// longest of its lockouts, the response doesn't tell which target it was for
+pub fn locked_for(ip: Option<IpAddr>) -> Option<Duration> {
+    let now = Instant::now();
+    let attempts = ATTEMPTS.lock().unwrap_or_else(|e| e.into_inner());
+    attempts
+        .clients
+        .failures
+        .keys()
+        .filter(|(client, _)| *client == ip)
+        .filter_map(|client| attempts.clients.locked_for(client, now))
+        .max()
+}
+
+// Unlocks a protected file with the given password, not giving a password isn't an attempt
+pub fn unlock(
+    file: &mut SfssFile,
+    password: Option<&str>,
+    ip: Option<IpAddr>,
+) -> Result<(), Status> {
+    if false == file.is_protected() {
+        return Ok(());
+    }
+    let password = password.ok_or(Status::Forbidden)?;
+    let target = file.hash.clone();
+    attempt(&target, ip, || file.unlock(Some(password)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn lockouts_double() {
+        let mut tracker = Tracker::new(2, 10 * SECOND);
+        let now = Instant::now();
+        assert_eq!(tracker.fail("a", now), None);
+        assert_eq!(tracker.fail("a", now), None);
+        assert!(false == tracker.is_locked(&"a", now));
+
+        assert_eq!(tracker.fail("a", now), Some(FIRST_LOCKOUT));
+        assert_eq!(tracker.locked_for(&"a", now + SECOND), Some(SECOND));
+        assert!(false == tracker.is_locked(&"a", now + FIRST_LOCKOUT));
+        assert_eq!(tracker.fail("a", now), Some(2 * FIRST_LOCKOUT));
+        assert_eq!(tracker.fail("a", now), Some(4 * FIRST_LOCKOUT));
+        // Capped at the maximum, also when doubling would overflow
+        for _ in 0..40 {
+            assert_eq!(tracker.fail("a", now), Some(10 * SECOND));
+        }
+        assert_eq!(tracker.failures(&"a"), 45);
+        // Other keys aren't affected
+        assert!(false == tracker.is_locked(&"b", now));
+        assert_eq!(tracker.failures(&"b"), 0);
+    }
+
+    #[test]
+    fn failures_are_forgotten() {
+        let mut tracker = Tracker::new(1, MAX_LOCKOUT);
+        let now = Instant::now();
+        tracker.fail("a", now);
+        assert!(tracker.fail("a", now).is_some());
+        // Long after the last failure the free attempts are back
+        assert_eq!(tracker.fail("a", now + FORGET_AFTER), None);
+        assert_eq!(tracker.failures(&"a"), 1);
+
+        tracker.fail("a", now + FORGET_AFTER);
+        assert!(tracker.is_locked(&"a", now + FORGET_AFTER));
+        tracker.forget(&"a");
+        assert!(false == tracker.is_locked(&"a", now + FORGET_AFTER));
+        assert_eq!(tracker.failures(&"a"), 0);
+    }
+
+    #[test]
+    fn entries_are_capped() {
+        let mut tracker = Tracker::new(1, MAX_LOCKOUT);
+        let now = Instant::now();
+        for i in 0..MAX_ENTRIES + 1 {
+            tracker.fail(i, now + i as u32 * SECOND);
+        }
+        assert!(tracker.failures.len() <= MAX_ENTRIES);
+        // The oldest ones made room, the newest is kept
+        assert_eq!(tracker.failures(&0), 0);
+        assert_eq!(tracker.failures(&MAX_ENTRIES), 1);
+    }
+}
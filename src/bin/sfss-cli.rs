@@ -0,0 +1,229 @@
+// Command line client for end-to-end encrypted pastes, using the same format
+// as resources/crypto.js so files work both ways between scripts and browsers.
+// Stored files are [1 byte version][12 byte iv][AES-256-GCM ciphertext], and
+// the plaintext is [2 byte little endian filename length][filename][content]
+//
+// sfss-cli upload [--url URL] [--protected] [--password PASSWORD] [--burn] [FILE]
+//     Encrypts FILE, or stdin without one, and prints the link with the key.
+//     A chosen password also protects the file
+// sfss-cli download LINK [OUTPUT]
+//     Decrypts the file behind LINK to OUTPUT, or stdout without one
+//
+// The url defaults to SFSS_URL, including the root if there is one
+
+use std::io::{Read, Write};
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Nonce};
+use serde::Deserialize;
+
+// Shared with the server for percent_encode, the rest of it is unused here
+#[allow(dead_code)]
+#[path = "../utils.rs"]
+mod utils;
+
+const VERSION: u8 = 1;
+const IV_LEN: usize = 12;
+
+#[derive(Deserialize)]
+struct Uploaded {
+    hash: String,
+    password: Option<String>,
+}
+
+fn encrypt(key: &[u8; 32], filename: &str, content: &[u8]) -> Result<Vec<u8>, String> {
+    let mut plaintext = Vec::with_capacity(2 + filename.len() + content.len());
+    plaintext.extend_from_slice(&(filename.len() as u16).to_le_bytes());
+    plaintext.extend_from_slice(filename.as_bytes());
+    plaintext.extend_from_slice(content);
+
+    let iv: [u8; IV_LEN] = rand::random();
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(key));
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&iv), plaintext.as_ref())
+        .map_err(|_| "encryption failed".to_string())?;
+
+    let mut data = vec![VERSION];
+    data.extend_from_slice(&iv);
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+}
+
+fn decrypt(key: &[u8], data: &[u8]) -> Result<(String, Vec<u8>), String> {
+    if key.len() != 32 {
+        return Err("the key in the link is invalid".into());
+    }
+    match data.first() {
+        Some(&VERSION) => (),
+        Some(version) => return Err(format!("unsupported encryption version {}", version)),
+        None => return Err("the file is empty".into()),
+    }
+    if data.len() < 1 + IV_LEN {
+        return Err("the file is too short".into());
+    }
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(key));
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(&data[1..1 + IV_LEN]), &data[1 + IV_LEN..])
+        .map_err(|_| "decryption failed, the key may be wrong".to_string())?;
+    if plaintext.len() < 2 {
+        return Err("the decrypted file is too short".into());
+    }
+    let name_len = u16::from_le_bytes([plaintext[0], plaintext[1]]) as usize;
+    let name = plaintext
+        .get(2..2 + name_len)
+        .ok_or_else(|| "the decrypted file is too short".to_string())?;
+    Ok((
+        String::from_utf8_lossy(name).into_owned(),
+        plaintext[2 + name_len..].to_vec(),
+    ))
+}
+
+// A multipart body with the given (name, filename, content) parts
+fn multipart(parts: &[(&str, Option<&str>, &[u8])]) -> (String, Vec<u8>) {
+    let boundary = format!("sfss-{:016x}", rand::random::<u64>());
+    let mut body = Vec::new();
+    for (name, filename, content) in parts {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        match filename {
+            Some(filename) => body.extend_from_slice(
+                format!(
+                    "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+                    name, filename
+                )
+                .as_bytes(),
+            ),
+            None => body.extend_from_slice(
+                format!("Content-Disposition: form-data; name=\"{}\"\r\n\r\n", name).as_bytes(),
+            ),
+        }
+        body.extend_from_slice(content);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    (boundary, body)
+}
+
+fn upload(args: &[String]) -> Result<(), String> {
+    let mut url = std::env::var("SFSS_URL").ok();
+    let mut protected = false;
+    let mut password = None;
+    let mut burn = false;
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--url" => url = args.next().cloned(),
+            "--protected" => protected = true,
+            "--password" => password = args.next().cloned(),
+            "--burn" => burn = true,
+            _ => path = Some(arg.clone()),
+        }
+    }
+    let url = url.ok_or_else(|| "no url given, use --url or set SFSS_URL".to_string())?;
+    let url = url.trim_end_matches('/');
+
+    let mut content = Vec::new();
+    let filename = match &path {
+        Some(path) => {
+            content = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+            std::path::Path::new(path)
+                .file_name()
+                .map_or("untitled.txt".into(), |name| {
+                    name.to_string_lossy().into_owned()
+                })
+        }
+        None => {
+            std::io::stdin()
+                .read_to_end(&mut content)
+                .map_err(|e| e.to_string())?;
+            "untitled.txt".into()
+        }
+    };
+
+    let key: [u8; 32] = rand::random();
+    let data = encrypt(&key, &filename, &content)?;
+    let mut parts: Vec<(&str, Option<&str>, &[u8])> = vec![("encrypted", None, &b"on"[..])];
+    if protected {
+        parts.push(("protected", None, &b"on"[..]));
+    }
+    if let Some(password) = &password {
+        parts.push(("password", None, password.as_bytes()));
+    }
+    if burn {
+        parts.push(("burn_after_read", None, &b"on"[..]));
+    }
+    parts.push(("file", Some("encrypted"), &data[..]));
+    let (boundary, body) = multipart(&parts);
+
+    let uploaded: Uploaded = ureq::post(&format!("{}/upload/api?json", url))
+        .set(
+            "Content-Type",
+            &format!("multipart/form-data; boundary={}", boundary),
+        )
+        .send_bytes(&body)
+        .map_err(|e| e.to_string())?
+        .into_string()
+        .map_err(|e| e.to_string())
+        .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()))?;
+
+    let key = base64::encode_config(&key, base64::URL_SAFE_NO_PAD);
+    match uploaded.password {
+        Some(password) => println!(
+            "{}/{}?password={}#{}",
+            url,
+            uploaded.hash,
+            utils::percent_encode(&password),
+            key
+        ),
+        None => println!("{}/{}#{}", url, uploaded.hash, key),
+    }
+    Ok(())
+}
+
+fn download(args: &[String]) -> Result<(), String> {
+    let link = args.get(0).ok_or_else(|| "no link given".to_string())?;
+    // The key is last, and never contains a #
+    let hash_idx = link
+        .rfind('#')
+        .ok_or_else(|| "the link has no key after the #".to_string())?;
+    let key = base64::decode_config(&link[hash_idx + 1..], base64::URL_SAFE_NO_PAD)
+        .map_err(|_| "the key in the link is invalid".to_string())?;
+    let link = &link[..hash_idx];
+    let raw = match link.find('?') {
+        Some(idx) => format!("{}/raw{}", &link[..idx], &link[idx..]),
+        None => format!("{}/raw", link),
+    };
+
+    let mut data = Vec::new();
+    ureq::get(&raw)
+        .call()
+        .map_err(|e| e.to_string())?
+        .into_reader()
+        .read_to_end(&mut data)
+        .map_err(|e| e.to_string())?;
+    let (filename, content) = decrypt(&key, &data)?;
+
+    match args.get(1) {
+        Some(output) => std::fs::write(output, &content).map_err(|e| format!("{}: {}", output, e)),
+        None => {
+            eprintln!("{}", filename);
+            std::io::stdout()
+                .write_all(&content)
+                .map_err(|e| e.to_string())
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let res = match args.get(0).map(String::as_str) {
+        Some("upload") => upload(&args[1..]),
+        Some("download") => download(&args[1..]),
+        _ => Err("usage: sfss-cli upload [--url URL] [--protected] [--password PASSWORD] [--burn] [FILE]\n       sfss-cli download LINK [OUTPUT]".into()),
+    };
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,168 @@
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+use std::io::{Cursor, Write};
+
+use serde::Serialize;
+
+use crate::highlight::highlight;
+use crate::markdown;
+use crate::sfss_format::filetype::{BinaryType, FileType};
+use crate::sfss_format::SfssFile;
+use crate::utils::percent_encode;
+
+// A bundle is stored as a regular file, with the hashes of its members as
+// the content, one per line
+
+#[derive(Serialize)]
+pub struct BundleEntry {
+    pub code: String,
+    pub filename: String,
+    // Already percent encoded
+    pub password: Option<String>,
+    pub hljsclass: Option<&'static str>,
+    // Rendered html for text, code and markdown files
+    pub content: Option<String>,
+}
+
+pub fn manifest(members: &[SfssFile]) -> Vec<u8> {
+    members
+        .iter()
+        .map(|member| member.hash.as_str())
+        .collect::<Vec<_>>()
+        .join("\n")
+        .into_bytes()
+}
+
+pub fn member_codes(bundle: &SfssFile) -> Vec<String> {
+    String::from_utf8_lossy(bundle.content())
+        .lines()
+        .filter(|line| false == line.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// Loads the members of the bundle with their content decompressed, the
+// bundle's own password gives access to all of them. Members can be deleted or
+// expire on their own, the bundle is left with whichever are still there
+pub fn load_members(bundle: &SfssFile) -> Vec<SfssFile> {
+    member_codes(bundle)
+        .into_iter()
+        .filter_map(|code| match load_member(bundle, code.clone()) {
+            Ok(member) => Some(member),
+            Err(e) if e.kind() == IoErrorKind::NotFound => None,
+            Err(e) => {
+                eprintln!(
+                    "Error loading member {} of bundle {}: {:?}",
+                    code, bundle.hash, e
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+fn load_member(bundle: &SfssFile, code: String) -> IoResult<SfssFile> {
+    let mut member = SfssFile::new(code, false)?;
+    if false == member.unlock(bundle.password.as_deref()) {
+        return Err(IoError::from(IoErrorKind::PermissionDenied));
+    }
+    member.decompress()?;
+    Ok(member)
+}
+
+// The codes of the members of a protected bundle, which get unlocked along with it
+pub fn unlock_members(code: String, password: &str) -> IoResult<Vec<String>> {
+    let mut bundle = SfssFile::new(code, false)?;
+    if false == bundle.unlock(Some(password)) {
+        return Err(IoError::from(IoErrorKind::PermissionDenied));
+    }
+    bundle.decompress()?;
+    Ok(member_codes(&bundle))
+}
+
+fn is_image(member: &SfssFile) -> bool {
+    member.filetype == FileType::Binary(BinaryType::Previewable)
+        && member
+            .mime
+            .as_ref()
+            .map_or(false, |mime| mime.starts_with("image/"))
+}
+
+fn entry(member: &SfssFile, with_password: bool) -> BundleEntry {
+    let text = String::from_utf8_lossy(member.content());
+    let (hljsclass, content) = match member.filetype {
+        FileType::Code(_) => {
+            let class = member.filetype.to_hljs();
+            let html = class
+                .and_then(|class| match highlight(class, &text) {
+                    Ok(html) => Some(html),
+                    Err(e) => {
+                        eprintln!("Error highlighting bundle member {}: {:?}", member.hash, e);
+                        None
+                    }
+                })
+                .unwrap_or_else(|| handlebars::html_escape(&text));
+            (class, Some(html))
+        }
+        FileType::Markdown => (None, Some(markdown::render(&text))),
+        FileType::Text => (None, Some(handlebars::html_escape(&text))),
+        _ => (None, None),
+    };
+    BundleEntry {
+        code: member.hash.clone(),
+        filename: member.filename.clone(),
+        password: member
+            .password
+            .as_deref()
+            .filter(|_| with_password)
+            .map(percent_encode),
+        hljsclass,
+        content,
+    }
+}
+
+// Splits the members into images, shown as a gallery, and everything else.
+// Their links only get the password if the bundle page got it in the query
+pub fn entries(members: &[SfssFile], with_password: bool) -> (Vec<BundleEntry>, Vec<BundleEntry>) {
+    let (images, files): (Vec<&SfssFile>, Vec<&SfssFile>) =
+        members.iter().partition(|member| is_image(member));
+    let to_entry = |member: &SfssFile| entry(member, with_password);
+    (
+        images.into_iter().map(to_entry).collect(),
+        files.into_iter().map(to_entry).collect(),
+    )
+}
+
+// Names inside the zip, without any directories and without duplicates
+fn zip_name(filename: &str, taken: &mut Vec<String>) -> String {
+    let base = filename
+        .rsplit(|c: char| c == '/' || c == '\\')
+        .next()
+        .unwrap_or("");
+    let base = if base.is_empty() { "untitled" } else { base };
+    let mut name = base.to_string();
+    let mut i = 1;
+    while taken.contains(&name) {
+        name = format!("{}-{}", i, base);
+        i += 1;
+    }
+    taken.push(name.clone());
+    name
+}
+
+pub fn zip(members: &[SfssFile]) -> IoResult<Vec<u8>> {
+    let to_io = |e: zip::result::ZipError| IoError::new(IoErrorKind::Other, e);
+    let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let mut taken = Vec::new();
+    for member in members {
+        writer
+            .start_file(
+                zip_name(&member.filename, &mut taken),
+                zip::write::FileOptions::default(),
+            )
+            .map_err(to_io)?;
+        writer.write_all(member.content())?;
+    }
+    Ok(writer.finish().map_err(to_io)?.into_inner())
+}
@@ -1,9 +1,167 @@
 use serde::{Deserialize, Serialize};
 
+use crate::archive::ArchiveEntry;
+use crate::bundle::BundleEntry;
+use crate::diff::{DiffLine, SplitRow};
+
+// Used for the links to raw, history and forking on rendered pages
+#[derive(Serialize)]
+pub struct LinksContext {
+    pub code: String,
+    pub webroot: String,
+    // Already percent encoded, only set if the page got it in the query
+    pub password: Option<String>,
+}
+
+// OpenGraph and Twitter card tags for link previews
+#[derive(Serialize)]
+pub struct MetaContext {
+    pub site: String,
+    pub url: String,
+    pub title: String,
+    pub description: String,
+    pub image: Option<String>,
+    pub oembed: String,
+}
+
 #[derive(Serialize)]
 pub struct CodeContext {
     pub hljsclass: &'static str,
     pub content: String,
+    pub links: Option<LinksContext>,
+    pub meta: Option<MetaContext>,
+}
+
+#[derive(Serialize)]
+pub struct MarkdownContext {
+    pub content: String,
+    pub source: String,
+    pub links: Option<LinksContext>,
+    pub meta: Option<MetaContext>,
+}
+
+#[derive(Serialize)]
+pub struct BundleContext {
+    pub images: Vec<BundleEntry>,
+    pub files: Vec<BundleEntry>,
+    pub links: Option<LinksContext>,
+    pub meta: Option<MetaContext>,
+}
+
+#[derive(Serialize)]
+pub struct ArchiveContext {
+    pub filename: String,
+    pub entries: Vec<ArchiveEntry>,
+    pub links: Option<LinksContext>,
+    pub meta: Option<MetaContext>,
+}
+
+#[derive(Serialize)]
+pub struct MediaContext {
+    pub filename: String,
+    pub size: String,
+    pub mime: String,
+    // One of image, video, audio and pdf
+    pub kind: &'static str,
+    pub links: Option<LinksContext>,
+    pub meta: Option<MetaContext>,
+}
+
+// The viewer for end-to-end encrypted files, the decryption happens in the browser
+#[derive(Serialize)]
+pub struct EncryptedContext {
+    pub filename: String,
+    pub webroot: String,
+    // Link to the ciphertext, with the password if the page got it in the query
+    pub raw: String,
+}
+
+#[derive(Serialize)]
+pub struct LoginContext {
+    pub webroot: String,
+    // Whether single sign-on is set up
+    pub sso: bool,
+    // Set after a wrong username or password was entered
+    pub wrong: bool,
+}
+
+#[derive(Serialize)]
+pub struct DashboardEntry {
+    pub code: String,
+    pub filename: String,
+    pub size: String,
+    pub public: bool,
+    pub protected: bool,
+    // How long until the file expires, like 3 days
+    pub expires: Option<String>,
+    // Like 2/5, for files with a view limit
+    pub views: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct DashboardContext {
+    pub webroot: String,
+    pub username: String,
+    pub extend_days: u64,
+    pub files: Vec<DashboardEntry>,
+}
+
+#[derive(Serialize)]
+pub struct UnlockContext {
+    pub code: String,
+    pub webroot: String,
+    // Set after a wrong password was entered
+    pub wrong: bool,
+}
+
+// Prefills the upload form with an existing file
+#[derive(Serialize)]
+pub struct ForkContext {
+    pub parent: String,
+    pub content: String,
+    // Display name of the language, as used in the language select
+    pub language: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct IndexContext<'a> {
+    #[serde(flatten)]
+    pub app: &'a AppContext,
+    pub fork: Option<ForkContext>,
+    // The logged in user
+    pub user: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct HistoryEntry {
+    // Hidden for protected revisions before the requested one
+    pub code: Option<String>,
+    pub filename: Option<String>,
+    pub protected: bool,
+    // The revision before this one, for linking to the diff
+    pub parent: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct HistoryContext {
+    pub code: String,
+    pub webroot: String,
+    pub revisions: Vec<HistoryEntry>,
+}
+
+#[derive(Serialize)]
+pub struct DiffContext {
+    pub old: String,
+    pub new: String,
+    pub webroot: String,
+    pub hljsclass: Option<&'static str>,
+    pub split: bool,
+    // Password query strings to carry over to links
+    pub query: String,
+    pub old_query: String,
+    pub new_query: String,
+    pub lines: Vec<DiffLine>,
+    pub rows: Vec<SplitRow>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -12,6 +170,9 @@ pub struct PageContext {
     pub url: String,
     pub webroot: String,
     pub password: Option<String>,
+    // Inline svg of the share link, only on the web upload page
+    #[serde(default)]
+    pub qr: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -20,5 +181,8 @@ pub struct AppContext {
     pub label: String,
     pub webroot: String,
     pub url: String,
+    pub content_url: Option<String>,
+    // Whether EXIF and similar metadata is removed from uploaded images by default
+    pub strip_metadata: bool,
     pub languages: Vec<String>,
 }
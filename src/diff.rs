@@ -0,0 +1,177 @@
+use serde::Serialize;
+use similar::{ChangeTag, TextDiff};
+
+use crate::highlight::{highlight, split_lines};
+
+// Lines of unchanged context shown around each change
+const CONTEXT_LINES: usize = 3;
+
+#[derive(Serialize, Clone)]
+pub struct DiffLine {
+    // One of equal, insert, delete and hunk
+    pub kind: &'static str,
+    pub old_no: Option<usize>,
+    pub new_no: Option<usize>,
+    pub content: String,
+}
+
+#[derive(Serialize)]
+pub struct SplitRow {
+    pub left: Option<DiffLine>,
+    pub right: Option<DiffLine>,
+}
+
+// Highlights the content if a language is given, and splits it into lines of html
+fn html_lines(content: &str, lang: Option<&str>) -> Vec<String> {
+    if let Some(lang) = lang {
+        match highlight(lang, content) {
+            Ok(html) => return split_lines(&html),
+            Err(e) => eprintln!("Error highlighting diff: {:?}", e),
+        }
+    }
+    content
+        .split('\n')
+        .map(|line| handlebars::html_escape(line))
+        .collect()
+}
+
+pub fn unified_lines(old: &str, new: &str, lang: Option<&str>) -> Vec<DiffLine> {
+    let old_lines = html_lines(old, lang);
+    let new_lines = html_lines(new, lang);
+    let line_html = |lines: &Vec<String>, idx: usize| lines.get(idx).cloned().unwrap_or_default();
+
+    let diff = TextDiff::from_lines(old, new);
+    let mut lines = Vec::new();
+    for group in diff.grouped_ops(CONTEXT_LINES) {
+        let (first, last) = (&group[0], &group[group.len() - 1]);
+        let old_range = first.old_range().start..last.old_range().end;
+        let new_range = first.new_range().start..last.new_range().end;
+        lines.push(DiffLine {
+            kind: "hunk",
+            old_no: None,
+            new_no: None,
+            content: format!(
+                "@@ -{},{} +{},{} @@",
+                old_range.start + 1,
+                old_range.len(),
+                new_range.start + 1,
+                new_range.len()
+            ),
+        });
+        for op in &group {
+            for change in diff.iter_changes(op) {
+                let (kind, content) = match change.tag() {
+                    ChangeTag::Equal => {
+                        ("equal", line_html(&old_lines, change.old_index().unwrap()))
+                    }
+                    ChangeTag::Delete => {
+                        ("delete", line_html(&old_lines, change.old_index().unwrap()))
+                    }
+                    ChangeTag::Insert => {
+                        ("insert", line_html(&new_lines, change.new_index().unwrap()))
+                    }
+                };
+                lines.push(DiffLine {
+                    kind,
+                    old_no: change.old_index().map(|i| i + 1),
+                    new_no: change.new_index().map(|i| i + 1),
+                    content,
+                });
+            }
+        }
+    }
+    lines
+}
+
+// Pairs up deleted and inserted lines so they end up next to each other
+pub fn split_rows(lines: Vec<DiffLine>) -> Vec<SplitRow> {
+    fn flush(rows: &mut Vec<SplitRow>, deletes: &mut Vec<DiffLine>, inserts: &mut Vec<DiffLine>) {
+        let mut deletes = deletes.drain(..);
+        let mut inserts = inserts.drain(..);
+        loop {
+            let (left, right) = (deletes.next(), inserts.next());
+            if left.is_none() && right.is_none() {
+                break;
+            }
+            rows.push(SplitRow { left, right });
+        }
+    }
+
+    let mut rows = Vec::new();
+    let mut deletes = Vec::new();
+    let mut inserts = Vec::new();
+    for line in lines {
+        match line.kind {
+            "delete" => deletes.push(line),
+            "insert" => inserts.push(line),
+            _ => {
+                flush(&mut rows, &mut deletes, &mut inserts);
+                rows.push(SplitRow {
+                    left: Some(line.clone()),
+                    right: Some(line),
+                });
+            }
+        }
+    }
+    flush(&mut rows, &mut deletes, &mut inserts);
+    rows
+}
+
+pub fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(CONTEXT_LINES)
+        .header(old_name, new_name)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(lines: &[DiffLine]) -> Vec<(&str, Option<usize>, Option<usize>, &str)> {
+        lines
+            .iter()
+            .map(|l| (l.kind, l.old_no, l.new_no, l.content.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn unified() {
+        let lines = unified_lines("a\n<b>\nc\n", "a\nx\nc\n", None);
+        assert_eq!(
+            summary(&lines),
+            vec![
+                ("hunk", None, None, "@@ -1,3 +1,3 @@"),
+                ("equal", Some(1), Some(1), "a"),
+                ("delete", Some(2), None, "&lt;b&gt;"),
+                ("insert", None, Some(2), "x"),
+                ("equal", Some(3), Some(3), "c"),
+            ]
+        );
+        assert!(unified_lines("same\n", "same\n", None).is_empty());
+    }
+
+    #[test]
+    fn split() {
+        let rows = split_rows(unified_lines("a\nb\nc\n", "a\nx\ny\nc\n", None));
+        let contents: Vec<_> = rows
+            .iter()
+            .map(|row| {
+                (
+                    row.left.as_ref().map(|l| l.content.as_str()),
+                    row.right.as_ref().map(|l| l.content.as_str()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            &contents[1..],
+            &[
+                (Some("a"), Some("a")),
+                (Some("b"), Some("x")),
+                (None, Some("y")),
+                (Some("c"), Some("c")),
+            ]
+        );
+    }
+}
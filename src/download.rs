@@ -0,0 +1,24 @@
+use rocket::http::{ContentType, Header};
+use rocket::response::{Responder, Response, Result as ResponseResult};
+use rocket::Request;
+use std::io::Cursor;
+
+// Generated content that is served as an attachment, like diffs and zips
+pub struct Download {
+    pub filename: String,
+    pub content_type: ContentType,
+    pub content: Vec<u8>,
+}
+
+impl<'r> Responder<'r, 'static> for Download {
+    fn respond_to(self, _: &'r Request<'_>) -> ResponseResult<'static> {
+        Response::build()
+            .header(self.content_type)
+            .header(Header::new(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.filename),
+            ))
+            .sized_body(self.content.len(), Cursor::new(self.content))
+            .ok()
+    }
+}
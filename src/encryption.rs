@@ -0,0 +1,356 @@
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hmac::Hmac;
+use sha2::Sha256;
+
+use crate::sfss_format::SfssFile;
+
+// Encryption at rest of stored files. Every file gets its own random data key,
+// which is kept in the header wrapped with the master key from the config, so
+// rotating the master key only rewrites the headers.
+// Wrapped keys are [8 byte master key id][12 byte nonce][encrypted data key],
+// encrypted bodies are [12 byte nonce][ciphertext] with the hash of the file
+// as associated data, so bodies can't be swapped between files
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const KEY_ID_LEN: usize = 8;
+const SALT_LEN: usize = 16;
+// PBKDF2 rounds for new password protected files, the count is stored with
+// each file so it can be raised later
+const PASSWORD_ROUNDS: u32 = 100_000;
+
+pub type DataKey = [u8; KEY_LEN];
+
+struct MasterKey {
+    id: [u8; KEY_ID_LEN],
+    cipher: Aes256Gcm,
+}
+
+impl MasterKey {
+    fn from_base64(var: &str, encoded: &str) -> Self {
+        let key = base64::decode(encoded.trim())
+            .ok()
+            .filter(|key| key.len() == KEY_LEN)
+            .unwrap_or_else(|| panic!("{} must be 32 bytes encoded as base64", var));
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&key));
+        // The tag of an empty message identifies the key without revealing anything about it
+        let check = cipher
+            .encrypt(Nonce::from_slice(&[0; NONCE_LEN]), &[][..])
+            .unwrap();
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&check[..KEY_ID_LEN]);
+        MasterKey { id, cipher }
+    }
+}
+
+lazy_static::lazy_static! {
+    // New files are encrypted with SFSS_ENCRYPTION_KEY, the keys in
+    // SFSS_OLD_ENCRYPTION_KEYS are only used to read files until they are rotated
+    static ref CURRENT: Option<MasterKey> = std::env::var("SFSS_ENCRYPTION_KEY")
+        .ok()
+        .filter(|key| false == key.is_empty())
+        .map(|key| MasterKey::from_base64("SFSS_ENCRYPTION_KEY", &key));
+    static ref OLD: Vec<MasterKey> = std::env::var("SFSS_OLD_ENCRYPTION_KEYS")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|key| false == key.trim().is_empty())
+        .map(|key| MasterKey::from_base64("SFSS_OLD_ENCRYPTION_KEYS", key))
+        .collect();
+    // Seals short-lived tokens like unlock cookies. Without SFSS_COOKIE_KEY the
+    // key is random, so tokens don't survive a restart
+    static ref TOKEN_KEY: Aes256Gcm = match std::env::var("SFSS_COOKIE_KEY") {
+        Ok(key) if false == key.is_empty() => MasterKey::from_base64("SFSS_COOKIE_KEY", &key).cipher,
+        _ => Aes256Gcm::new(GenericArray::from_slice(&new_data_key())),
+    };
+}
+
+// Reads the keys right away, so invalid ones stop sfss at startup instead of
+// failing the first request that needs them
+pub fn init() {
+    lazy_static::initialize(&CURRENT);
+    lazy_static::initialize(&OLD);
+    lazy_static::initialize(&TOKEN_KEY);
+}
+
+fn crypto_error(_: aes_gcm::aead::Error) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, "decryption failed")
+}
+
+pub fn enabled() -> bool {
+    CURRENT.is_some()
+}
+
+pub fn new_data_key() -> DataKey {
+    rand::random()
+}
+
+fn seal(cipher: &Aes256Gcm, aad: &[u8], plaintext: &[u8]) -> IoResult<Vec<u8>> {
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| IoError::new(IoErrorKind::Other, "encryption failed"))?;
+    let mut res = nonce.to_vec();
+    res.extend_from_slice(&ciphertext);
+    Ok(res)
+}
+
+fn open(cipher: &Aes256Gcm, aad: &[u8], sealed: &[u8]) -> IoResult<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return Err(IoError::from(IoErrorKind::InvalidData));
+    }
+    cipher
+        .decrypt(
+            Nonce::from_slice(&sealed[..NONCE_LEN]),
+            Payload {
+                msg: &sealed[NONCE_LEN..],
+                aad,
+            },
+        )
+        .map_err(crypto_error)
+}
+
+// Wraps a data key with the current master key
+pub fn wrap(data_key: &DataKey) -> IoResult<Vec<u8>> {
+    let master = CURRENT
+        .as_ref()
+        .ok_or_else(|| IoError::new(IoErrorKind::Other, "no encryption key configured"))?;
+    wrap_with(master, data_key)
+}
+
+fn wrap_with(master: &MasterKey, data_key: &DataKey) -> IoResult<Vec<u8>> {
+    let mut res = master.id.to_vec();
+    res.extend_from_slice(&seal(&master.cipher, &master.id, data_key)?);
+    Ok(res)
+}
+
+pub fn unwrap(wrapped: &[u8]) -> IoResult<DataKey> {
+    unwrap_with(CURRENT.iter().chain(OLD.iter()), wrapped)
+}
+
+// Unwraps with whichever of the master keys the data key was wrapped with
+fn unwrap_with<'a>(
+    mut masters: impl Iterator<Item = &'a MasterKey>,
+    wrapped: &[u8],
+) -> IoResult<DataKey> {
+    if wrapped.len() < KEY_ID_LEN {
+        return Err(IoError::from(IoErrorKind::InvalidData));
+    }
+    let (id, sealed) = wrapped.split_at(KEY_ID_LEN);
+    let master = masters.find(|master| master.id == id).ok_or_else(|| {
+        IoError::new(
+            IoErrorKind::PermissionDenied,
+            "file is encrypted with an unknown key",
+        )
+    })?;
+    let key = open(&master.cipher, id, sealed)?;
+    if key.len() != KEY_LEN {
+        return Err(IoError::from(IoErrorKind::InvalidData));
+    }
+    let mut res = [0; KEY_LEN];
+    res.copy_from_slice(&key);
+    Ok(res)
+}
+
+// Whether the data key is already wrapped with the current master key
+pub fn is_current(wrapped: &[u8]) -> bool {
+    CURRENT
+        .as_ref()
+        .map_or(false, |master| wrapped.starts_with(&master.id))
+}
+
+pub fn encrypt_body(data_key: &DataKey, hash: &str, body: &[u8]) -> IoResult<Vec<u8>> {
+    seal(
+        &Aes256Gcm::new(GenericArray::from_slice(data_key)),
+        hash.as_bytes(),
+        body,
+    )
+}
+
+pub fn decrypt_body(data_key: &DataKey, hash: &str, body: &[u8]) -> IoResult<Vec<u8>> {
+    open(
+        &Aes256Gcm::new(GenericArray::from_slice(data_key)),
+        hash.as_bytes(),
+        body,
+    )
+}
+
+// Tokens are encrypted as well as signed, since they can hold passwords
+pub fn seal_token(aad: &[u8], plaintext: &[u8]) -> IoResult<Vec<u8>> {
+    seal(&TOKEN_KEY, aad, plaintext)
+}
+
+pub fn open_token(aad: &[u8], sealed: &[u8]) -> IoResult<Vec<u8>> {
+    open(&TOKEN_KEY, aad, sealed)
+}
+
+// Password protected files have their data key wrapped with a key derived
+// from the password instead, stored as [4 byte rounds][16 byte salt][nonce][encrypted data key].
+// The server never stores the password itself, so the body can't be read without it
+fn password_cipher(password: &str, salt: &[u8], rounds: u32) -> Aes256Gcm {
+    Aes256Gcm::new(GenericArray::from_slice(&derive_key(
+        password, salt, rounds,
+    )))
+}
+
+// PBKDF2 is slow on purpose, and runs for every request with a password. Called
+// on an async worker, the worker hands its other tasks off until it is done
+pub fn derive_key(password: &str, salt: &[u8], rounds: u32) -> [u8; KEY_LEN] {
+    let mut key = [0; KEY_LEN];
+    rocket::tokio::task::block_in_place(|| {
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, rounds, &mut key)
+    });
+    key
+}
+
+pub fn wrap_with_password(data_key: &DataKey, password: &str) -> IoResult<Vec<u8>> {
+    let salt: [u8; SALT_LEN] = rand::random();
+    let mut res = PASSWORD_ROUNDS.to_le_bytes().to_vec();
+    res.extend_from_slice(&salt);
+    let cipher = password_cipher(password, &salt, PASSWORD_ROUNDS);
+    res.extend_from_slice(&seal(&cipher, &salt, data_key)?);
+    Ok(res)
+}
+
+// None if the password is wrong
+pub fn unwrap_with_password(wrapped: &[u8], password: &str) -> Option<DataKey> {
+    if wrapped.len() < 4 + SALT_LEN {
+        return None;
+    }
+    let rounds = u32::from_le_bytes([wrapped[0], wrapped[1], wrapped[2], wrapped[3]]);
+    let salt = &wrapped[4..4 + SALT_LEN];
+    let key = open(
+        &password_cipher(password, salt, rounds),
+        salt,
+        &wrapped[4 + SALT_LEN..],
+    )
+    .ok()?;
+    if key.len() != KEY_LEN {
+        return None;
+    }
+    let mut res = [0; KEY_LEN];
+    res.copy_from_slice(&key);
+    Some(res)
+}
+
+// The random salt of a password wrapped key, it also goes into the hash of the
+// file so protected uploads never share a code with other uploads
+pub fn password_salt(wrapped: &[u8]) -> &[u8] {
+    wrapped.get(4..4 + SALT_LEN).unwrap_or(&[])
+}
+
+// Wraps the data key of every stored file with the current master key. Files
+// without encryption are left alone, run with the server stopped.
+// Returns how many files were rewrapped and how many failed
+pub fn rotate_keys() -> IoResult<(usize, usize)> {
+    if false == enabled() {
+        return Err(IoError::new(
+            IoErrorKind::Other,
+            "SFSS_ENCRYPTION_KEY has to be set to the new key",
+        ));
+    }
+    let (mut rewrapped, mut failed) = (0, 0);
+    for entry in std::fs::read_dir(std::env::var("SFSS_LOCATION").unwrap())? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        // Thumbnails and temporary files have an extension, stored files never do
+        if name.contains('.') {
+            continue;
+        }
+        match SfssFile::rewrap_key(name.clone()) {
+            Ok(true) => rewrapped += 1,
+            Ok(false) => (),
+            Err(e) => {
+                eprintln!("Error rewrapping the key of {}: {:?}", name, e);
+                failed += 1;
+            }
+        }
+    }
+    Ok((rewrapped, failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn master_key(byte: u8) -> MasterKey {
+        MasterKey::from_base64("TEST_KEY", &base64::encode([byte; KEY_LEN]))
+    }
+
+    #[test]
+    fn rotation() {
+        let (old, new) = (master_key(1), master_key(2));
+        assert_ne!(old.id, new.id);
+        let data_key = new_data_key();
+
+        let wrapped = wrap_with(&old, &data_key).unwrap();
+        assert!(wrapped.starts_with(&old.id));
+        // After rotating the old key is only used for reading
+        let rewrapped = wrap_with(
+            &new,
+            &unwrap_with(vec![&new, &old].into_iter(), &wrapped).unwrap(),
+        )
+        .unwrap();
+        assert!(rewrapped.starts_with(&new.id));
+        assert_eq!(
+            unwrap_with(vec![&new].into_iter(), &rewrapped).unwrap(),
+            data_key
+        );
+        assert_eq!(
+            unwrap_with(vec![&new].into_iter(), &wrapped)
+                .unwrap_err()
+                .kind(),
+            IoErrorKind::PermissionDenied
+        );
+
+        let mut tampered = rewrapped.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(unwrap_with(vec![&new].into_iter(), &tampered).is_err());
+        assert!(unwrap_with(vec![&new].into_iter(), &rewrapped[..4]).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "TEST_KEY must be 32 bytes")]
+    fn invalid_master_key() {
+        MasterKey::from_base64("TEST_KEY", "c2hvcnQ=");
+    }
+
+    #[test]
+    fn bodies() {
+        let data_key = new_data_key();
+        let body = encrypt_body(&data_key, "hash", b"content").unwrap();
+        assert_eq!(decrypt_body(&data_key, "hash", &body).unwrap(), b"content");
+        // Bodies can't be moved to another file or read with another key
+        assert!(decrypt_body(&data_key, "other", &body).is_err());
+        assert!(decrypt_body(&new_data_key(), "hash", &body).is_err());
+        assert!(decrypt_body(&data_key, "hash", &body[..NONCE_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn passwords() {
+        let data_key = new_data_key();
+        let wrapped = wrap_with_password(&data_key, "hunter2").unwrap();
+        assert_eq!(unwrap_with_password(&wrapped, "hunter2"), Some(data_key));
+        assert_eq!(unwrap_with_password(&wrapped, "hunter3"), None);
+        assert_eq!(unwrap_with_password(&wrapped[..10], "hunter2"), None);
+        assert_eq!(password_salt(&wrapped), &wrapped[4..4 + SALT_LEN]);
+        assert!(password_salt(&wrapped[..10]).is_empty());
+    }
+
+    #[test]
+    fn tokens() {
+        let token = seal_token(b"code", b"secret").unwrap();
+        assert_eq!(open_token(b"code", &token).unwrap(), b"secret");
+        assert!(open_token(b"other", &token).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+use std::io::Result as IoResult;
+use std::time::Duration;
+
+use crate::sfss_format::SfssFile;
+
+// Expired files are deleted by a sweep through every stored file, which runs
+// in the background at launch and then every few minutes. Until then they are
+// only hidden, reading a file never deletes it
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Deletes every expired file, returns how many there were
+fn sweep() -> IoResult<usize> {
+    let mut removed = 0;
+    for entry in std::fs::read_dir(std::env::var("SFSS_LOCATION").unwrap())? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        // Thumbnails, temporary files and the like have an extension, stored files never do
+        if name.contains('.') {
+            continue;
+        }
+        match SfssFile::stored(name.clone()) {
+            Ok(file) if file.is_expired() => match file.remove() {
+                Ok(()) => removed += 1,
+                Err(e) => eprintln!("Error deleting expired file {}: {:?}", name, e),
+            },
+            _ => (),
+        }
+    }
+    Ok(removed)
+}
+
+pub fn spawn_sweeper() {
+    std::thread::spawn(|| loop {
+        match sweep() {
+            Ok(0) => (),
+            Ok(removed) => eprintln!("Deleted {} expired files", removed),
+            Err(e) => eprintln!("Error deleting expired files: {:?}", e),
+        }
+        std::thread::sleep(SWEEP_INTERVAL);
+    });
+}
@@ -0,0 +1,83 @@
+use std::io::Result as IoResult;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+
+const HLJS_SOCKET: &str = "/tmp/sfss/sfss.sock";
+
+// Sends the content to the HighlightJS server, and returns the highlighted html
+pub fn highlight(lang: &str, content: &str) -> IoResult<String> {
+    let mut stream = UnixStream::connect(HLJS_SOCKET)?;
+    write!(stream, "{}:{}", lang, content)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+// Looks up the hljs class for a language name as used in markdown fences
+// and similar places, which can be either the class or the display name
+pub fn hljs_class(name: &str) -> Option<&'static str> {
+    highlightjs_rs::to_id(name)
+        .or_else(|| highlightjs_rs::exact(name).and_then(|m| highlightjs_rs::to_id(m)))
+        .and_then(highlightjs_rs::from_id)
+}
+
+// Splits highlighted html into lines, closing any spans still open at the end
+// of a line and reopening them on the next, so each line stands on its own
+pub fn split_lines(html: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut open: Vec<&str> = Vec::new();
+    let mut line = String::new();
+    let mut rest = html;
+    while false == rest.is_empty() {
+        if rest.starts_with("<span") {
+            let end = rest.find('>').map_or(rest.len(), |i| i + 1);
+            open.push(&rest[..end]);
+            line.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if rest.starts_with("</span>") {
+            open.pop();
+            line.push_str("</span>");
+            rest = &rest["</span>".len()..];
+        } else if rest.starts_with('\n') {
+            line.push_str(&"</span>".repeat(open.len()));
+            lines.push(std::mem::replace(&mut line, open.concat()));
+            rest = &rest[1..];
+        } else {
+            let end = rest
+                .find(|c| c == '<' || c == '\n')
+                .unwrap_or(rest.len())
+                .max(1);
+            line.push_str(&rest[..end]);
+            rest = &rest[end..];
+        }
+    }
+    lines.push(line);
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_plain_lines() {
+        assert_eq!(split_lines("a\nb"), vec!["a", "b"]);
+        assert_eq!(split_lines("a\n"), vec!["a", ""]);
+        assert_eq!(split_lines(""), vec![""]);
+        assert_eq!(split_lines("1 &lt; 2\n<b>"), vec!["1 &lt; 2", "<b>"]);
+    }
+
+    #[test]
+    fn split_open_spans() {
+        let html = "<span class=\"a\">x\n<span class=\"b\">y\nz</span></span>\nw";
+        assert_eq!(
+            split_lines(html),
+            vec![
+                "<span class=\"a\">x</span>",
+                "<span class=\"a\"><span class=\"b\">y</span></span>",
+                "<span class=\"a\"><span class=\"b\">z</span></span>",
+                "w",
+            ]
+        );
+    }
+}
@@ -1,20 +1,53 @@
+mod accounts;
+mod apikeys;
+mod archive;
+mod attempts;
+mod bundle;
 mod context;
+mod diff;
+mod download;
+mod encryption;
+mod expiry;
+mod highlight;
+mod markdown;
+mod oidc;
+mod password;
+mod qr;
+mod quota;
+mod ratelimit;
+mod security;
 mod sfss_format;
 mod sfss_templates;
+mod thumbnail;
+mod unfurl;
+mod unlock;
 #[macro_use]
 mod utils;
+mod views;
 
 #[macro_use]
 extern crate rocket;
 extern crate lazy_static;
 
 use rocket::{
-    http::Status,
-    response::content::{Html, Json},
+    form::Form,
+    http::{ContentType, CookieJar, Status},
+    response::content::{Css, Html, JavaScript, Json},
+    response::Redirect,
 };
 
-use context::{AppContext, PageContext};
-use sfss_format::SfssFile;
+use accounts::{LoginForm, Page, Session};
+use context::{
+    AppContext, DashboardContext, DashboardEntry, DiffContext, ForkContext, HistoryContext,
+    HistoryEntry, IndexContext, LoginContext, PageContext,
+};
+use download::Download;
+use ratelimit::{Downloads, RateLimit, Uploads};
+use sfss_format::{filetype::FileType, SfssFile, UploadError};
+use unlock::{Credentials, Locked, UnlockForm};
+use utils::percent_encode;
+
+use std::net::IpAddr;
 
 lazy_static::lazy_static! {
     static ref APP_CONTEXT: AppContext = {
@@ -24,6 +57,9 @@ lazy_static::lazy_static! {
             label: std::env::var("SFSS_LABEL").unwrap(),
             webroot: std::env::var("SFSS_ROOT").unwrap(),
             url: std::env::var("SFSS_URL").unwrap(),
+            content_url: std::env::var("SFSS_CONTENT_URL").ok(),
+            strip_metadata: std::env::var("SFSS_STRIP_METADATA")
+                .map_or(true, |v| v != "false" && v != "0"),
             languages: serde_json::from_str(highlightjs_rs::LANGSJSON).unwrap(),
         }
     };
@@ -31,12 +67,18 @@ lazy_static::lazy_static! {
 
 fn upload(data: SfssFile, api: bool) -> Result<Html<String>, Status> {
     let passworded = data.password.is_some();
-    let ctx = PageContext {
+    let mut ctx = PageContext {
         code: data.hash, //sfss_file.hash,
         url: APP_CONTEXT.url.clone(),
         webroot: APP_CONTEXT.webroot.clone(),
         password: data.password,
+        qr: None,
     };
+    if false == api {
+        ctx.qr = qr::svg(&qr::share_url(&ctx))
+            .map_err(|e| eprintln!("Error creating qr code for {}: {:?}", ctx.code, e))
+            .ok();
+    }
     match handlebars::Handlebars::new()
         .render_template(sfss_templates::get_template(api, passworded), &ctx)
     {
@@ -49,45 +91,138 @@ fn upload(data: SfssFile, api: bool) -> Result<Html<String>, Status> {
 }
 
 #[post("/upload", data = "<data>")]
-fn upload_web(data: SfssFile) -> Result<Html<String>, Status> {
-    upload(data, false)
+fn upload_web(
+    _limit: RateLimit<Uploads>,
+    data: Result<SfssFile, UploadError>,
+) -> Result<Html<String>, UploadError> {
+    Ok(upload(data?, false)?)
 }
 
 #[post("/upload/api", data = "<data>")]
-fn upload_api(data: SfssFile) -> Result<Html<String>, Status> {
-    upload(data, true)
+fn upload_api(
+    _limit: RateLimit<Uploads>,
+    data: Result<SfssFile, UploadError>,
+) -> Result<Html<String>, UploadError> {
+    Ok(upload(data?, true)?)
 }
 
 #[post("/upload/api?json", data = "<data>")]
-fn upload_json(data: SfssFile) -> Result<Json<String>, Status> {
-    if let Some(password) = data.password {
-        Ok(Json(
-            format!(
-                "{{\"hash\": \"{}\", \"password\": \"{}\"}}",
-                data.hash, password
-            )
-            .to_owned(),
-        ))
-    } else {
-        Ok(Json(
-            format!("{{\"hash\": \"{}\", \"password\": null}}", data.hash).to_owned(),
-        ))
+fn upload_json(
+    _limit: RateLimit<Uploads>,
+    data: Result<SfssFile, UploadError>,
+) -> Result<Json<String>, UploadError> {
+    let data = data?;
+    let res = serde_json::json!({ "hash": data.hash, "password": data.password });
+    Ok(Json(res.to_string()))
+}
+
+// How much the API key, user or client of the request has stored, and its quota
+#[get("/upload/api/quota")]
+fn upload_quota(caller: quota::Caller) -> Result<Json<String>, Status> {
+    match serde_json::to_string(&caller.report()) {
+        Ok(v) => Ok(Json(v)),
+        Err(_) => Err(Status::InternalServerError),
     }
 }
 
 #[get("/<code>/raw?<password>")]
-fn raw(code: String, password: Option<String>) -> Result<SfssFile, Status> {
-    file(code, password)
+fn raw(
+    code: String,
+    password: Option<String>,
+    credentials: Credentials,
+    _limit: RateLimit<Downloads>,
+) -> Result<SfssFile, Status> {
+    let password = credentials.password(password, &code);
+    load_file(code, password, credentials.ip)
 }
 #[get("/<code>?<password>")]
-fn file(code: String, password: Option<String>) -> Result<SfssFile, Status> {
-    match SfssFile::new(code.clone(), false) {
-        Ok(file) => {
-            if let Some(_) = file.password {
-                if file.password != password {
-                    return Err(Status::Forbidden);
-                };
+fn file(
+    code: String,
+    password: Option<String>,
+    credentials: Credentials,
+    _limit: RateLimit<Downloads>,
+) -> Result<SfssFile, Locked> {
+    // Views of files with a view limit are counted when the file is sent
+    let password = credentials.password(password, &code);
+    let wrong = password.is_some();
+    load_file(code.clone(), password, credentials.ip).map_err(|status| match status {
+        Status::Forbidden => unlock::page(&code, wrong),
+        status => Locked::Status(status),
+    })
+}
+
+// Checks the password from the unlock form, and keeps it in a cookie for the file
+#[post("/<code>/unlock", data = "<form>")]
+async fn unlock_file(
+    code: String,
+    form: Form<UnlockForm>,
+    cookies: &CookieJar<'_>,
+    credentials: Credentials,
+) -> Result<Redirect, Locked> {
+    let password = form.into_inner().password;
+    let mut file = match SfssFile::new(code.clone(), true) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Error unlocking file with code {}: {:?}", &code, e);
+            return Err(Locked::Status(Status::InternalServerError));
+        }
+    };
+    if false == file.is_protected() {
+        return Err(unlock::page(&code, true));
+    }
+    match attempts::unlock(&mut file, password.as_deref(), credentials.ip) {
+        Ok(()) => {
+            let password = password.unwrap_or_default();
+            let cookie = unlock::cookie(&code, &password).map_err(|e| {
+                eprintln!("Error creating unlock cookie for code {}: {:?}", &code, e);
+                Locked::Status(Status::InternalServerError)
+            })?;
+            cookies.add(cookie);
+            if file.filetype == FileType::Bundle {
+                let members = bundle::unlock_members(code.clone(), &password).map_err(|e| {
+                    eprintln!("Error unlocking the members of bundle {}: {:?}", &code, e);
+                    Locked::Status(Status::InternalServerError)
+                })?;
+                for member in members {
+                    match unlock::cookie(&member, &password) {
+                        Ok(cookie) => cookies.add(cookie),
+                        Err(e) => {
+                            eprintln!("Error creating unlock cookie for code {}: {:?}", &member, e)
+                        }
+                    }
+                }
             }
+            // A 303, so reloading the page doesn't send the form again
+            Ok(Redirect::to(format!("{}/{}", APP_CONTEXT.webroot, code)))
+        }
+        Err(Status::Forbidden) => Err(unlock::page(&code, true)),
+        Err(status) => Err(Locked::Status(status)),
+    }
+}
+
+// Files with a view limit can only be read through file and raw, so that every
+// view is counted, everything else uses this
+fn open_file(
+    code: String,
+    password: Option<String>,
+    ip: Option<IpAddr>,
+) -> Result<SfssFile, Status> {
+    let file = load_file(code, password, ip)?;
+    if file.max_views.is_some() {
+        return Err(Status::Forbidden);
+    }
+    Ok(file)
+}
+
+fn load_file(
+    code: String,
+    password: Option<String>,
+    ip: Option<IpAddr>,
+) -> Result<SfssFile, Status> {
+    match SfssFile::new(code.clone(), false) {
+        Ok(mut file) => {
+            // Also decrypts protected files
+            attempts::unlock(&mut file, password.as_deref(), ip)?;
             Ok(file)
         }
         Err(e) => {
@@ -97,9 +232,347 @@ fn file(code: String, password: Option<String>) -> Result<SfssFile, Status> {
     }
 }
 
-#[get("/")]
-fn root() -> Result<Html<String>, Status> {
-    match handlebars::Handlebars::new().render_template(sfss_templates::INDEX, &*APP_CONTEXT) {
+// Ranked after the diff routes, /diff/<old>/<new> would match this as well
+#[get("/<code>/entry/<path..>?<password>", rank = 2)]
+fn archive_entry(
+    code: String,
+    path: std::path::PathBuf,
+    password: Option<String>,
+    credentials: Credentials,
+    _limit: RateLimit<Downloads>,
+) -> Result<SfssFile, Status> {
+    let mut file = open_file(
+        code.clone(),
+        credentials.password(password, &code),
+        credentials.ip,
+    )?;
+    if let Err(e) = file.decompress() {
+        eprintln!("Error decompressing archive {}: {:?}", &code, e);
+        return Err(Status::InternalServerError);
+    }
+    let kind = archive::kind(&file).ok_or(Status::NotFound)?;
+    let path = path.to_string_lossy();
+    match archive::read_entry(kind, file.content(), &path) {
+        Ok(Some(content)) => {
+            let filename = path.rsplit('/').next().unwrap_or(&path).to_string();
+            let mut entry = SfssFile::transient(filename, content);
+            entry.flags.no_preview = file.flags.no_preview;
+            Ok(entry)
+        }
+        Ok(None) => Err(Status::NotFound),
+        Err(e) => {
+            eprintln!("Error reading {} from archive {}: {:?}", &path, &code, e);
+            Err(Status::UnprocessableEntity)
+        }
+    }
+}
+
+#[get("/<code>/thumb?<w>&<password>")]
+fn thumb(
+    code: String,
+    w: Option<u32>,
+    password: Option<String>,
+    credentials: Credentials,
+    _limit: RateLimit<Downloads>,
+) -> Result<(ContentType, Vec<u8>), Status> {
+    let mut file = match SfssFile::new(code.clone(), true) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Error serving thumbnail for code {}: {:?}", &code, e);
+            return Err(Status::InternalServerError);
+        }
+    };
+    let password = credentials.password(password, &code);
+    attempts::unlock(&mut file, password.as_deref(), credentials.ip)?;
+    if file.max_views.is_some() {
+        return Err(Status::Forbidden);
+    }
+    let is_image = file
+        .mime
+        .as_ref()
+        .map_or(false, |mime| mime.starts_with("image/"));
+    if false == is_image {
+        return Err(Status::NotFound);
+    }
+    thumbnail::thumbnail(&file, thumbnail::snap_width(w)).map_err(|e| {
+        eprintln!("Error creating thumbnail for code {}: {:?}", &code, e);
+        Status::UnprocessableEntity
+    })
+}
+
+// QR code of the share link, the password is only included if the given one is right
+#[get("/<code>/qr?<password>&<format>")]
+fn qr_code(
+    code: String,
+    password: Option<String>,
+    format: Option<String>,
+    credentials: Credentials,
+    _limit: RateLimit<Downloads>,
+) -> Result<(ContentType, Vec<u8>), Status> {
+    let mut file = match SfssFile::new(code.clone(), true) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Error serving qr code for code {}: {:?}", &code, e);
+            return Err(Status::InternalServerError);
+        }
+    };
+    if password.is_some() {
+        if false == file.is_protected() {
+            return Err(Status::Forbidden);
+        }
+        attempts::unlock(&mut file, password.as_deref(), credentials.ip)?;
+    }
+    let url = qr::share_url(&PageContext {
+        code: code.clone(),
+        url: APP_CONTEXT.url.clone(),
+        webroot: APP_CONTEXT.webroot.clone(),
+        password,
+        qr: None,
+    });
+    let res = match format.as_deref() {
+        None | Some("svg") => qr::svg(&url).map(|svg| (ContentType::SVG, svg.into_bytes())),
+        Some("png") => qr::png(&url).map(|png| (ContentType::PNG, png)),
+        Some(_) => return Err(Status::BadRequest),
+    };
+    res.map_err(|e| {
+        eprintln!("Error creating qr code for code {}: {:?}", &code, e);
+        Status::InternalServerError
+    })
+}
+
+// oEmbed for link previews, protected files and files with a view limit are
+// reported as unauthorized no matter what the url contains
+#[get("/oembed?<url>&<format>")]
+fn oembed(
+    url: String,
+    format: Option<String>,
+    _limit: RateLimit<Downloads>,
+) -> Result<Json<String>, Status> {
+    if format.map_or(false, |format| format != "json") {
+        return Err(Status::NotImplemented);
+    }
+    let code = unfurl::code_from_url(&url).ok_or(Status::NotFound)?;
+    let file = SfssFile::new(code, true).map_err(|_| Status::NotFound)?;
+    if unfurl::is_private(&file) {
+        return Err(Status::Unauthorized);
+    }
+    match serde_json::to_string(&unfurl::oembed(&file)) {
+        Ok(v) => Ok(Json(v)),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[get("/<code>/zip?<password>")]
+fn bundle_zip(
+    code: String,
+    password: Option<String>,
+    credentials: Credentials,
+    _limit: RateLimit<Downloads>,
+) -> Result<Download, Status> {
+    let mut file = open_file(
+        code.clone(),
+        credentials.password(password, &code),
+        credentials.ip,
+    )?;
+    if file.filetype != FileType::Bundle {
+        return Err(Status::NotFound);
+    }
+    let zip = file
+        .decompress()
+        .and_then(|_| bundle::zip(&bundle::load_members(&file)));
+    match zip {
+        Ok(content) => Ok(Download {
+            filename: format!("{}.zip", code),
+            content_type: ContentType::ZIP,
+            content,
+        }),
+        Err(e) => {
+            eprintln!("Error zipping bundle {}: {:?}", &code, e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+// Loads both sides of a diff, which only makes sense for text
+fn diff_files(
+    old: String,
+    new: String,
+    old_password: Option<String>,
+    new_password: Option<String>,
+    ip: Option<IpAddr>,
+) -> Result<(SfssFile, SfssFile), Status> {
+    let mut old = open_file(old, old_password, ip)?;
+    let mut new = open_file(new, new_password, ip)?;
+    if let FileType::Binary(_) = old.filetype {
+        return Err(Status::UnprocessableEntity);
+    }
+    if let FileType::Binary(_) = new.filetype {
+        return Err(Status::UnprocessableEntity);
+    }
+    if let Err(e) = old.decompress().and_then(|_| new.decompress()) {
+        eprintln!("Error decompressing files for diff: {:?}", e);
+        return Err(Status::InternalServerError);
+    }
+    Ok((old, new))
+}
+
+#[get("/diff/<old>/<new>?<view>&<old_password>&<new_password>")]
+fn diff_page(
+    old: String,
+    new: String,
+    view: Option<String>,
+    old_password: Option<String>,
+    new_password: Option<String>,
+    credentials: Credentials,
+    _limit: RateLimit<Downloads>,
+) -> Result<Html<String>, Status> {
+    let query = |name: &str, password: &Option<String>| {
+        password
+            .as_ref()
+            .map(|p| format!("{}={}", name, percent_encode(p)))
+    };
+    let ctx_query: String = [
+        query("old_password", &old_password),
+        query("new_password", &new_password),
+    ]
+    .iter()
+    .flatten()
+    .map(|q| format!("&{}", q))
+    .collect();
+    let old_query = query("password", &old_password).map_or(String::new(), |q| format!("?{}", q));
+    let new_query = query("password", &new_password).map_or(String::new(), |q| format!("?{}", q));
+
+    let (old_file, new_file) = diff_files(
+        old.clone(),
+        new.clone(),
+        old_password,
+        new_password,
+        credentials.ip,
+    )?;
+    let hljsclass = old_file
+        .filetype
+        .to_hljs()
+        .or_else(|| new_file.filetype.to_hljs());
+    let lines = diff::unified_lines(
+        &String::from_utf8_lossy(old_file.content()),
+        &String::from_utf8_lossy(new_file.content()),
+        hljsclass,
+    );
+    let split = view.as_deref() == Some("split");
+    let ctx = DiffContext {
+        old,
+        new,
+        webroot: APP_CONTEXT.webroot.clone(),
+        hljsclass,
+        split,
+        query: ctx_query,
+        old_query,
+        new_query,
+        rows: if split {
+            diff::split_rows(lines)
+        } else {
+            Vec::new()
+        },
+        lines: if split { Vec::new() } else { lines },
+    };
+    match handlebars::Handlebars::new().render_template(sfss_templates::DIFF, &ctx) {
+        Ok(v) => Ok(Html(v)),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[get("/diff/<old>/<new>/raw?<old_password>&<new_password>")]
+fn diff_raw(
+    old: String,
+    new: String,
+    old_password: Option<String>,
+    new_password: Option<String>,
+    credentials: Credentials,
+    _limit: RateLimit<Downloads>,
+) -> Result<Download, Status> {
+    let (old_file, new_file) = diff_files(
+        old.clone(),
+        new.clone(),
+        old_password,
+        new_password,
+        credentials.ip,
+    )?;
+    Ok(Download {
+        filename: format!("{}-{}.diff", old, new),
+        content_type: ContentType::new("text", "x-diff"),
+        content: diff::unified_diff(
+            &String::from_utf8_lossy(old_file.content()),
+            &String::from_utf8_lossy(new_file.content()),
+            &format!("a/{}", old_file.filename),
+            &format!("b/{}", new_file.filename),
+        )
+        .into_bytes(),
+    })
+}
+
+// How many revisions the history page follows back at most
+const MAX_HISTORY: usize = 100;
+
+#[get("/<code>/history?<password>")]
+fn history(
+    code: String,
+    password: Option<String>,
+    credentials: Credentials,
+    _limit: RateLimit<Downloads>,
+) -> Result<Html<String>, Status> {
+    // Only checks the password of the requested file
+    open_file(
+        code.clone(),
+        credentials.password(password, &code),
+        credentials.ip,
+    )?;
+
+    let mut revisions: Vec<HistoryEntry> = Vec::new();
+    let mut seen: Vec<String> = Vec::new();
+    let mut next = Some(code.clone());
+    while let Some(current) = next.take() {
+        if revisions.len() >= MAX_HISTORY || seen.contains(&current) {
+            break;
+        }
+        seen.push(current.clone());
+        let file = match SfssFile::new(current.clone(), true) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Error reading revision {} of {}: {:?}", &current, &code, e);
+                break;
+            }
+        };
+        let protected = file.is_protected();
+        next = file.parent.clone();
+        revisions.push(HistoryEntry {
+            // Whoever has the requested file doesn't necessarily have the password
+            // of the ones before it, so they don't even get something to guess it on
+            code: Some(current).filter(|current| false == protected || *current == code),
+            filename: if protected { None } else { Some(file.filename) },
+            protected,
+            parent: file.parent,
+        });
+    }
+    // Diffs can only be linked when neither side needs a password
+    for i in 0..revisions.len() {
+        let parent_protected = revisions.get(i + 1).map_or(true, |p| p.protected);
+        if revisions[i].protected || parent_protected {
+            revisions[i].parent = None;
+        }
+    }
+
+    let ctx = HistoryContext {
+        code,
+        webroot: APP_CONTEXT.webroot.clone(),
+        revisions,
+    };
+    match handlebars::Handlebars::new().render_template(sfss_templates::HISTORY, &ctx) {
         Ok(v) => Ok(Html(v)),
         Err(e) => {
             eprintln!("{:?}", e);
@@ -108,17 +581,296 @@ fn root() -> Result<Html<String>, Status> {
     }
 }
 
+// Loads an existing file to prefill the upload form with
+fn fork_context(
+    code: String,
+    password: Option<String>,
+    ip: Option<IpAddr>,
+) -> Result<ForkContext, Status> {
+    let mut file = open_file(code.clone(), password, ip)?;
+    if let FileType::Binary(_) = file.filetype {
+        return Err(Status::UnprocessableEntity);
+    }
+    if let Err(e) = file.decompress() {
+        eprintln!("Error decompressing file {} for forking: {:?}", &code, e);
+        return Err(Status::InternalServerError);
+    }
+    let class = match file.filetype {
+        FileType::Markdown => Some("markdown"),
+        _ => file.filetype.to_hljs(),
+    };
+    let language = class.and_then(|class| {
+        APP_CONTEXT
+            .languages
+            .iter()
+            .find(|l| highlightjs_rs::exact(l) == Some(class))
+            .cloned()
+    });
+    Ok(ForkContext {
+        parent: code,
+        content: String::from_utf8_lossy(file.content()).into_owned(),
+        language,
+    })
+}
+
+fn index(fork: Option<ForkContext>, session: Option<Session>) -> Result<Html<String>, Status> {
+    let ctx = IndexContext {
+        app: &*APP_CONTEXT,
+        fork,
+        user: session.map(|s| s.username),
+    };
+    match handlebars::Handlebars::new().render_template(sfss_templates::INDEX, &ctx) {
+        Ok(v) => Ok(Html(v)),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+// The upload form prefilled with an existing file, which reads it like a download
+#[get("/?<fork>&<password>")]
+fn fork(
+    fork: String,
+    password: Option<String>,
+    credentials: Credentials,
+    session: Option<Session>,
+    _limit: RateLimit<Downloads>,
+) -> Result<Html<String>, Status> {
+    index(Some(fork_context(fork, password, credentials.ip)?), session)
+}
+
+#[get("/")]
+fn root(session: Option<Session>) -> Result<Html<String>, Status> {
+    index(None, session)
+}
+
+fn login_page(wrong: bool) -> Page {
+    let ctx = LoginContext {
+        webroot: APP_CONTEXT.webroot.clone(),
+        sso: oidc::enabled(),
+        wrong,
+    };
+    match handlebars::Handlebars::new().render_template(sfss_templates::LOGIN, &ctx) {
+        Ok(v) => Page::Html(Html(v)),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            Page::Status(Status::InternalServerError)
+        }
+    }
+}
+
+fn dashboard_url() -> Redirect {
+    Redirect::to(format!("{}/dashboard", APP_CONTEXT.webroot))
+}
+
+#[get("/login")]
+fn login_form(session: Option<Session>) -> Page {
+    match session {
+        Some(_) => Page::Redirect(dashboard_url()),
+        None => login_page(false),
+    }
+}
+
+#[post("/login", data = "<form>")]
+async fn login(form: Form<LoginForm>, cookies: &CookieJar<'_>, credentials: Credentials) -> Page {
+    let LoginForm { username, password } = form.into_inner();
+    let res = attempts::attempt(&accounts::owner(&username), credentials.ip, || {
+        accounts::verify(&username, &password)
+    });
+    match res {
+        Ok(()) => match accounts::session_cookie(&username) {
+            Ok(cookie) => {
+                cookies.add(cookie);
+                Page::Redirect(dashboard_url())
+            }
+            Err(e) => {
+                eprintln!("Error creating session cookie: {:?}", e);
+                Page::Status(Status::InternalServerError)
+            }
+        },
+        Err(Status::Forbidden) => login_page(true),
+        Err(status) => Page::Status(status),
+    }
+}
+
+#[get("/login/oidc")]
+async fn oidc_login(cookies: &CookieJar<'_>) -> Result<Redirect, Status> {
+    // Talking to the provider blocks
+    let (url, cookie) = rocket::tokio::task::spawn_blocking(oidc::start)
+        .await
+        .map_err(|e| {
+            eprintln!("Error starting single sign-on: {:?}", e);
+            Status::InternalServerError
+        })??;
+    cookies.add(cookie);
+    Ok(Redirect::to(url))
+}
+
+#[get("/login/oidc/callback?<code>&<state>")]
+async fn oidc_callback(
+    code: Option<String>,
+    state: Option<String>,
+    cookies: &CookieJar<'_>,
+) -> Page {
+    let pending = cookies.get(oidc::COOKIE).map(|c| c.value().to_string());
+    cookies.remove(oidc::removed_cookie());
+    let (code, state) = match (code, state) {
+        (Some(code), Some(state)) => (code, state),
+        // The provider sends an error instead, like when the user declined
+        _ => return Page::Status(Status::Unauthorized),
+    };
+    // Talking to the provider blocks
+    let identity = rocket::tokio::task::spawn_blocking(move || {
+        oidc::finish(&code, &state, pending.as_deref())
+    })
+    .await;
+    let username = match identity {
+        Ok(Ok(identity)) => accounts::oidc_user(&identity.subject, &identity.username),
+        Ok(Err(status)) => Err(status),
+        Err(e) => {
+            eprintln!("Error finishing single sign-on: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    };
+    let cookie = match username.map(|username| accounts::session_cookie(&username)) {
+        Ok(Ok(cookie)) => cookie,
+        Ok(Err(e)) => {
+            eprintln!("Error creating session cookie: {:?}", e);
+            return Page::Status(Status::InternalServerError);
+        }
+        Err(status) => return Page::Status(status),
+    };
+    cookies.add(cookie);
+    // Browsers don't send the strict session cookie along when redirected
+    // from the provider's site, a refresh from this page is same site though
+    Page::Html(Html(format!(
+        "<html><head><meta http-equiv=\"refresh\" content=\"0;url={}/dashboard\"></head></html>",
+        APP_CONTEXT.webroot
+    )))
+}
+
+#[post("/logout")]
+fn logout(cookies: &CookieJar<'_>) -> Redirect {
+    cookies.remove(accounts::removed_session_cookie());
+    Redirect::to(format!("{}/", APP_CONTEXT.webroot))
+}
+
+#[get("/dashboard")]
+fn dashboard(session: Option<Session>) -> Page {
+    let session = match session {
+        Some(session) => session,
+        None => return Page::Redirect(Redirect::to(format!("{}/login", APP_CONTEXT.webroot))),
+    };
+    let files = match accounts::owned_files(&session.username) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Error listing files of {}: {:?}", session.username, e);
+            return Page::Status(Status::InternalServerError);
+        }
+    };
+    let now = utils::unix_time();
+    let ctx = DashboardContext {
+        webroot: APP_CONTEXT.webroot.clone(),
+        extend_days: accounts::EXTEND_DAYS,
+        files: files
+            .into_iter()
+            .map(|(file, size)| DashboardEntry {
+                protected: file.is_protected(),
+                expires: file
+                    .expires
+                    .map(|expires| utils::human_duration(expires.saturating_sub(now))),
+                views: file
+                    .max_views
+                    .map(|max_views| format!("{}/{}", file.views, max_views)),
+                public: file.flags.public,
+                size: utils::human_size(size),
+                filename: file.filename,
+                code: file.hash,
+            })
+            .collect(),
+        username: session.username,
+    };
+    match handlebars::Handlebars::new().render_template(sfss_templates::DASHBOARD, &ctx) {
+        Ok(v) => Page::Html(Html(v)),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            Page::Status(Status::InternalServerError)
+        }
+    }
+}
+
+// A file of the logged in user, with its body so it can be written again
+fn owned_file(code: &str, session: &Session) -> Result<SfssFile, Status> {
+    let file = match SfssFile::new(code.to_string(), false) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Error loading file {} for the dashboard: {:?}", code, e);
+            return Err(Status::NotFound);
+        }
+    };
+    if file.owner != Some(accounts::owner(&session.username)) {
+        return Err(Status::NotFound);
+    }
+    Ok(file)
+}
+
+#[post("/dashboard/<code>/delete")]
+fn dashboard_delete(code: String, session: Session) -> Result<Redirect, Status> {
+    owned_file(&code, &session)?.remove().map_err(|e| {
+        eprintln!("Error deleting file {}: {:?}", code, e);
+        Status::InternalServerError
+    })?;
+    Ok(dashboard_url())
+}
+
+#[post("/dashboard/<code>/visibility")]
+fn dashboard_visibility(code: String, session: Session) -> Result<Redirect, Status> {
+    let mut file = owned_file(&code, &session)?;
+    file.flags.public = false == file.flags.public;
+    file.rewrite().map_err(|e| {
+        eprintln!("Error changing visibility of file {}: {:?}", code, e);
+        Status::InternalServerError
+    })?;
+    Ok(dashboard_url())
+}
+
+// Only for files that expire, starting from now if that is later
+#[post("/dashboard/<code>/extend")]
+fn dashboard_extend(code: String, session: Session) -> Result<Redirect, Status> {
+    let mut file = owned_file(&code, &session)?;
+    let expires = file.expires.ok_or(Status::BadRequest)?;
+    file.expires = Some(expires.max(utils::unix_time()) + accounts::EXTEND_DAYS * 24 * 60 * 60);
+    file.rewrite().map_err(|e| {
+        eprintln!("Error extending the expiry of file {}: {:?}", code, e);
+        Status::InternalServerError
+    })?;
+    Ok(dashboard_url())
+}
+
+// These need their proper content types, as they are served with nosniff
 #[get("/hljs.js")]
-fn hljs() -> &'static str {
-    include_str!(concat!(
+fn hljs() -> JavaScript<&'static str> {
+    JavaScript(include_str!(concat!(
         env!("CARGO_MANIFEST_DIR"),
         "/resources/highlight.js"
-    ))
+    )))
+}
+
+#[get("/crypto.js")]
+fn crypto() -> JavaScript<&'static str> {
+    JavaScript(include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/resources/crypto.js"
+    )))
 }
 
 #[get("/style.css")]
-fn style() -> &'static str {
-    include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/style.css"))
+fn style() -> Css<&'static str> {
+    Css(include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/resources/style.css"
+    )))
 }
 
 #[get("/favicon.ico")]
@@ -145,34 +897,109 @@ fn langs_api() -> &'static str {
     highlightjs_rs::CLASSRAW
 }
 
-// The launch attribute, tells that this is the entry point for the application
-#[launch]
-async fn rocket() -> rocket::Rocket {
-    dotenv::dotenv().ok();
+const USAGE: &str = "usage: sfss
+       sfss rotate-keys
+       sfss user COMMAND
+       sfss api-key COMMAND";
+
+// Commands for managing sfss from the command line, which run instead of the server
+fn command(args: &[String]) -> Option<Result<(), String>> {
+    let (name, rest) = args.split_first()?;
+    Some(match name.as_str() {
+        // sfss rotate-keys rewraps the data keys of every file with SFSS_ENCRYPTION_KEY
+        "rotate-keys" => match encryption::rotate_keys() {
+            Ok((rewrapped, 0)) => {
+                println!("Rewrapped the keys of {} files", rewrapped);
+                Ok(())
+            }
+            Ok((rewrapped, failed)) => Err(format!(
+                "Rewrapped the keys of {} files, {} failed",
+                rewrapped, failed
+            )),
+            Err(e) => Err(format!("Error rotating keys: {:?}", e)),
+        },
+        // sfss user manages the accounts
+        "user" => accounts::command(rest),
+        // sfss api-key manages the keys for uploading
+        "api-key" => apikeys::command(rest),
+        _ => Err(USAGE.into()),
+    })
+}
+
+fn rocket() -> rocket::Rocket {
+    // Settings that would otherwise only fail once a request needs them
+    encryption::init();
+    password::init();
+    expiry::spawn_sweeper();
     rocket::ignite()
         .mount(
             "/",
             routes![
                 file,
                 raw,
+                unlock_file,
+                archive_entry,
+                bundle_zip,
+                thumb,
+                oembed,
+                qr_code,
+                diff_page,
+                diff_raw,
+                history,
                 upload_api,
                 upload_json,
+                upload_quota,
                 upload_web,
+                login_form,
+                login,
+                oidc_login,
+                oidc_callback,
+                logout,
+                dashboard,
+                dashboard_delete,
+                dashboard_visibility,
+                dashboard_extend,
+                fork,
                 root,
                 favicon,
                 style,
                 hljs,
+                crypto,
                 robots,
                 langs,
                 langs_api
             ],
         )
         .attach(rocket::fairing::AdHoc::on_response(
-            "Allow CORS",
-            |_, res| {
+            "Retry-After",
+            |req, res| Box::pin(async move { ratelimit::retry_after(req, res) }),
+        ))
+        .attach(rocket::fairing::AdHoc::on_response(
+            "Security headers",
+            |req, res| {
                 Box::pin(async move {
-                    res.adjoin_raw_header("Access-Control-Allow-Origin", "*");
+                    res.set_raw_header("X-Content-Type-Options", "nosniff");
+                    if security::allows_cors(req.uri().path()) {
+                        res.adjoin_raw_header("Access-Control-Allow-Origin", "*");
+                    }
                 })
             },
         ))
 }
+
+#[rocket::main]
+async fn main() {
+    dotenv::dotenv().ok();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(res) = command(&args) {
+        if let Err(e) = res {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Err(e) = rocket().launch().await {
+        eprintln!("Error launching sfss: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,71 @@
+use pulldown_cmark::{html, CodeBlockKind, CowStr, Event, Options, Parser, Tag};
+
+use crate::highlight::{highlight, hljs_class};
+
+// Renders markdown to html, fenced code blocks get highlighted the same way
+// as code files, and the result is sanitized since the source is user content
+pub fn render(source: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+
+    let mut events = Vec::new();
+    // (language, content) of the code block currently being read
+    let mut code_block: Option<(String, String)> = None;
+    for event in Parser::new_ext(source, options) {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let lang = match kind {
+                    CodeBlockKind::Fenced(info) => {
+                        info.split_whitespace().next().unwrap_or("").to_string()
+                    }
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code_block = Some((lang, String::new()));
+            }
+            Event::Text(text) if code_block.is_some() => {
+                code_block.as_mut().unwrap().1.push_str(&text);
+            }
+            Event::End(Tag::CodeBlock(_)) => {
+                if let Some((lang, content)) = code_block.take() {
+                    events.push(Event::Html(CowStr::from(render_code(&lang, &content))));
+                }
+            }
+            event => events.push(event),
+        }
+    }
+
+    let mut unsanitized = String::new();
+    html::push_html(&mut unsanitized, events.into_iter());
+    ammonia::Builder::default()
+        .add_tag_attributes("pre", &["class"])
+        .add_tag_attributes("code", &["class"])
+        .add_tag_attributes("span", &["class"])
+        .clean(&unsanitized)
+        .to_string()
+}
+
+fn render_code(lang: &str, content: &str) -> String {
+    match hljs_class(lang) {
+        Some(class) => match highlight(class, content) {
+            Ok(highlighted) => format!(
+                "<pre><code class=\"{} hljs\">{}</code></pre>",
+                class, highlighted
+            ),
+            Err(e) => {
+                eprintln!("Error highlighting markdown code block: {:?}", e);
+                plain_code(content)
+            }
+        },
+        None => plain_code(content),
+    }
+}
+
+fn plain_code(content: &str) -> String {
+    format!(
+        "<pre><code class=\"hljs\">{}</code></pre>",
+        handlebars::html_escape(content)
+    )
+}
@@ -0,0 +1,384 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rocket::http::{Cookie, SameSite, Status};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::encryption;
+use crate::utils::{percent_encode, unix_time};
+
+// Single sign-on with an OpenID Connect provider, using the authorization code
+// flow with PKCE. Enabled by setting SFSS_OIDC_ISSUER and SFSS_OIDC_CLIENT_ID,
+// identities are mapped to sfss users by their subject, see accounts::oidc_user.
+// The id token comes straight from the token endpoint over TLS, so only its
+// claims are checked and not its signature, as OpenID Connect allows
+pub const COOKIE: &str = "sfss_oidc";
+// How long the provider has to send the user back
+const PENDING_LIFETIME: u64 = 10 * 60;
+// Requests to the provider run on blocking threads, which an unresponsive
+// provider shouldn't be able to hold on to
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+struct Config {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    scopes: String,
+    username_claim: String,
+    groups_claim: String,
+    // Empty allows every identity the provider accepts
+    allowed_groups: Vec<String>,
+}
+
+#[derive(Deserialize, Clone)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+}
+
+// Kept in a sealed cookie between the redirect to the provider and the callback
+#[derive(Serialize, Deserialize)]
+struct Pending {
+    state: String,
+    nonce: String,
+    verifier: String,
+    expires: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    id_token: String,
+}
+
+pub struct Identity {
+    pub subject: String,
+    pub username: String,
+}
+
+fn env(var: &str) -> Option<String> {
+    std::env::var(var)
+        .ok()
+        .filter(|v| false == v.trim().is_empty())
+}
+
+lazy_static::lazy_static! {
+    static ref CONFIG: Option<Config> = match (env("SFSS_OIDC_ISSUER"), env("SFSS_OIDC_CLIENT_ID")) {
+        (Some(issuer), Some(client_id)) => Some(Config {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id,
+            client_secret: env("SFSS_OIDC_CLIENT_SECRET"),
+            scopes: env("SFSS_OIDC_SCOPES").unwrap_or_else(|| "openid profile".into()),
+            username_claim: env("SFSS_OIDC_USERNAME_CLAIM")
+                .unwrap_or_else(|| "preferred_username".into()),
+            groups_claim: env("SFSS_OIDC_GROUPS_CLAIM").unwrap_or_else(|| "groups".into()),
+            allowed_groups: env("SFSS_OIDC_ALLOWED_GROUPS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|g| g.trim().to_string())
+                .filter(|g| false == g.is_empty())
+                .collect(),
+        }),
+        _ => None,
+    };
+    // Fetched on the first login, the provider's endpoints rarely change
+    static ref DISCOVERY: Mutex<Option<Discovery>> = Mutex::new(None);
+    static ref AGENT: ureq::Agent = ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(REQUEST_TIMEOUT)
+        .timeout_write(REQUEST_TIMEOUT)
+        .build();
+}
+
+pub fn enabled() -> bool {
+    CONFIG.is_some()
+}
+
+fn config() -> Result<&'static Config, Status> {
+    CONFIG.as_ref().ok_or(Status::NotFound)
+}
+
+fn random_string() -> String {
+    base64::encode_config(&rand::random::<[u8; 32]>(), base64::URL_SAFE_NO_PAD)
+}
+
+fn redirect_uri() -> String {
+    format!(
+        "{}{}/login/oidc/callback",
+        crate::APP_CONTEXT.url,
+        crate::APP_CONTEXT.webroot
+    )
+}
+
+fn get_json(request: ureq::Request) -> Result<Value, String> {
+    let body = request
+        .call()
+        .map_err(|e| e.to_string())?
+        .into_string()
+        .map_err(|e| e.to_string())?;
+    serde_json::from_str(&body).map_err(|e| e.to_string())
+}
+
+// The lock isn't held while fetching, two logins at once may both fetch it
+fn discovery(config: &Config) -> Result<Discovery, Status> {
+    if let Some(discovery) = DISCOVERY.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        return Ok(discovery.clone());
+    }
+    let url = format!("{}/.well-known/openid-configuration", config.issuer);
+    let discovery: Discovery = get_json(AGENT.get(&url))
+        .and_then(|json| serde_json::from_value(json).map_err(|e| e.to_string()))
+        .map_err(|e| {
+            eprintln!(
+                "Error fetching the OpenID configuration from {}: {}",
+                url, e
+            );
+            Status::BadGateway
+        })?;
+    *DISCOVERY.lock().unwrap_or_else(|e| e.into_inner()) = Some(discovery.clone());
+    Ok(discovery)
+}
+
+// The url of the provider to send the user to, and the cookie to remember the
+// login by. This blocks on fetching the provider's configuration the first time
+pub fn start() -> Result<(String, Cookie<'static>), Status> {
+    let config = config()?;
+    let discovery = discovery(config)?;
+    let pending = Pending {
+        state: random_string(),
+        nonce: random_string(),
+        verifier: random_string(),
+        expires: unix_time() + PENDING_LIFETIME,
+    };
+    let challenge = base64::encode_config(
+        &Sha256::digest(pending.verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    );
+    let separator = if discovery.authorization_endpoint.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    let url = format!(
+        "{}{}response_type=code&client_id={}&redirect_uri={}&scope={}&state={}&nonce={}&code_challenge={}&code_challenge_method=S256",
+        discovery.authorization_endpoint,
+        separator,
+        percent_encode(&config.client_id),
+        percent_encode(&redirect_uri()),
+        percent_encode(&config.scopes),
+        pending.state,
+        pending.nonce,
+        challenge
+    );
+    let json = serde_json::to_vec(&pending).map_err(|_| Status::InternalServerError)?;
+    let sealed = encryption::seal_token(COOKIE.as_bytes(), &json).map_err(|e| {
+        eprintln!("Error sealing the OpenID Connect login: {:?}", e);
+        Status::InternalServerError
+    })?;
+    // Lax, as the provider sends the user back with a cross site redirect
+    let cookie = Cookie::build(
+        COOKIE,
+        base64::encode_config(&sealed, base64::URL_SAFE_NO_PAD),
+    )
+    .path(format!("{}/login/oidc", crate::APP_CONTEXT.webroot))
+    .http_only(true)
+    .secure(crate::APP_CONTEXT.url.starts_with("https://"))
+    .same_site(SameSite::Lax)
+    .finish();
+    Ok((url, cookie))
+}
+
+pub fn removed_cookie() -> Cookie<'static> {
+    Cookie::build(COOKIE, "")
+        .path(format!("{}/login/oidc", crate::APP_CONTEXT.webroot))
+        .finish()
+}
+
+fn open_pending(cookie: &str) -> Option<Pending> {
+    let sealed = base64::decode_config(cookie, base64::URL_SAFE_NO_PAD).ok()?;
+    let json = encryption::open_token(COOKIE.as_bytes(), &sealed).ok()?;
+    serde_json::from_slice::<Pending>(&json)
+        .ok()
+        .filter(|pending| pending.expires >= unix_time())
+}
+
+// The claims of the id token, which has to be for this client and this login
+fn id_token_claims(config: &Config, id_token: &str, nonce: &str) -> Result<Value, String> {
+    let payload = id_token.split('.').nth(1).ok_or("malformed id token")?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| "malformed id token")?;
+    let claims: Value = serde_json::from_slice(&payload).map_err(|e| e.to_string())?;
+    let issuer = claims["iss"].as_str().unwrap_or("").trim_end_matches('/');
+    if issuer != config.issuer {
+        return Err(format!("id token from the wrong issuer {}", issuer));
+    }
+    let audience_matches = match &claims["aud"] {
+        Value::String(aud) => aud == &config.client_id,
+        Value::Array(aud) => aud
+            .iter()
+            .any(|a| a.as_str() == Some(config.client_id.as_str())),
+        _ => false,
+    };
+    if false == audience_matches {
+        return Err("id token for another client".into());
+    }
+    if claims["nonce"].as_str() != Some(nonce) {
+        return Err("id token for another login".into());
+    }
+    if claims["exp"].as_u64().map_or(true, |exp| exp < unix_time()) {
+        return Err("id token expired".into());
+    }
+    Ok(claims)
+}
+
+// Exchanges the code from the callback for the identity of the user, this
+// blocks on requests to the provider
+pub fn finish(code: &str, state: &str, cookie: Option<&str>) -> Result<Identity, Status> {
+    let config = config()?;
+    let pending = match cookie.and_then(open_pending) {
+        Some(pending) if pending.state == state => pending,
+        _ => return Err(Status::BadRequest),
+    };
+    let discovery = discovery(config)?;
+
+    let redirect_uri = redirect_uri();
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", redirect_uri.as_str()),
+        ("client_id", config.client_id.as_str()),
+        ("code_verifier", pending.verifier.as_str()),
+    ];
+    if let Some(secret) = &config.client_secret {
+        form.push(("client_secret", secret.as_str()));
+    }
+    let tokens: TokenResponse = AGENT
+        .post(&discovery.token_endpoint)
+        .send_form(&form)
+        .map_err(|e| e.to_string())
+        .and_then(|res| res.into_string().map_err(|e| e.to_string()))
+        .and_then(|body| serde_json::from_str(&body).map_err(|e| e.to_string()))
+        .map_err(|e| {
+            eprintln!("Error exchanging the OpenID Connect code: {}", e);
+            Status::BadGateway
+        })?;
+    let mut claims = id_token_claims(config, &tokens.id_token, &pending.nonce).map_err(|e| {
+        eprintln!("Refused OpenID Connect login: {}", e);
+        Status::Forbidden
+    })?;
+
+    // Providers often only put the username and groups in the userinfo
+    if let (Some(endpoint), Some(token)) = (&discovery.userinfo_endpoint, &tokens.access_token) {
+        match get_json(
+            AGENT
+                .get(endpoint)
+                .set("Authorization", &format!("Bearer {}", token)),
+        ) {
+            Ok(Value::Object(userinfo)) if userinfo.get("sub") == claims.get("sub") => {
+                for (claim, value) in userinfo {
+                    claims[claim.as_str()] = value;
+                }
+            }
+            Ok(_) => eprintln!("Ignored OpenID Connect userinfo for another subject"),
+            Err(e) => eprintln!("Error fetching OpenID Connect userinfo: {}", e),
+        }
+    }
+
+    let subject = claims["sub"].as_str().ok_or(Status::Forbidden)?.to_string();
+    let username = claims[config.username_claim.as_str()]
+        .as_str()
+        .unwrap_or(&subject)
+        .to_string();
+    if false == config.allowed_groups.is_empty() {
+        let groups: Vec<&str> = claims[config.groups_claim.as_str()]
+            .as_array()
+            .map_or(Vec::new(), |groups| {
+                groups.iter().filter_map(|g| g.as_str()).collect()
+            });
+        if false
+            == config
+                .allowed_groups
+                .iter()
+                .any(|g| groups.contains(&g.as_str()))
+        {
+            eprintln!(
+                "Refused OpenID Connect login of {}, not in an allowed group",
+                username
+            );
+            return Err(Status::Forbidden);
+        }
+    }
+    Ok(Identity { subject, username })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            issuer: "https://id.example.com".into(),
+            client_id: "sfss".into(),
+            client_secret: None,
+            scopes: "openid".into(),
+            username_claim: "preferred_username".into(),
+            groups_claim: "groups".into(),
+            allowed_groups: Vec::new(),
+        }
+    }
+
+    // Only the payload is looked at, the signature isn't checked
+    fn token(claims: Value) -> String {
+        let payload = base64::encode_config(claims.to_string().as_bytes(), base64::URL_SAFE_NO_PAD);
+        format!("eyJhbGciOiJSUzI1NiJ9.{}.signature", payload)
+    }
+
+    fn claims(iss: &str, aud: Value, nonce: &str, exp: u64) -> Value {
+        serde_json::json!({ "iss": iss, "aud": aud, "nonce": nonce, "exp": exp, "sub": "1234" })
+    }
+
+    #[test]
+    fn valid_tokens() {
+        let exp = unix_time() + 60;
+        let valid = id_token_claims(
+            &config(),
+            &token(claims("https://id.example.com/", "sfss".into(), "n", exp)),
+            "n",
+        )
+        .unwrap();
+        assert_eq!(valid["sub"], "1234");
+        // The audience can be a list that includes other clients
+        let aud = serde_json::json!(["other", "sfss"]);
+        assert!(id_token_claims(
+            &config(),
+            &token(claims("https://id.example.com", aud, "n", exp)),
+            "n"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn invalid_tokens() {
+        let exp = unix_time() + 60;
+        let check = |payload: Value| id_token_claims(&config(), &token(payload), "n");
+        assert!(check(claims("https://evil.example.com", "sfss".into(), "n", exp)).is_err());
+        assert!(check(claims("https://id.example.com", "other".into(), "n", exp)).is_err());
+        assert!(check(claims("https://id.example.com", Value::Null, "n", exp)).is_err());
+        assert!(check(claims("https://id.example.com", "sfss".into(), "m", exp)).is_err());
+        assert!(check(claims(
+            "https://id.example.com",
+            "sfss".into(),
+            "n",
+            exp - 120
+        ))
+        .is_err());
+        assert!(check(serde_json::json!({ "iss": "https://id.example.com" })).is_err());
+
+        assert!(id_token_claims(&config(), "not a token", "n").is_err());
+        assert!(id_token_claims(&config(), "a.!!!.c", "n").is_err());
+        assert!(id_token_claims(&config(), "a.bm90IGpzb24.c", "n").is_err());
+    }
+}
@@ -0,0 +1,76 @@
+use passwords::{analyzer, scorer, PasswordGenerator};
+
+// Generated passwords and the policy for passwords chosen by uploaders.
+// SFSS_PASSWORD_CHARSET is a comma separated list of lowercase, uppercase,
+// numbers and symbols
+const DEFAULT_LENGTH: usize = 8;
+const DEFAULT_CHARSET: &str = "lowercase,uppercase,numbers";
+
+pub struct PasswordPolicy {
+    generator: PasswordGenerator,
+    // Chosen passwords need at least this many characters
+    min_length: usize,
+    // And at least this score from the passwords crate, 0 to 100
+    min_score: f64,
+}
+
+fn env_or<T: std::str::FromStr>(var: &str, default: T) -> T {
+    match std::env::var(var) {
+        Ok(value) if false == value.trim().is_empty() => value
+            .trim()
+            .parse()
+            .unwrap_or_else(|_| panic!("{} has an invalid value", var)),
+        _ => default,
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref POLICY: PasswordPolicy = {
+        let charset = std::env::var("SFSS_PASSWORD_CHARSET")
+            .ok()
+            .filter(|v| false == v.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_CHARSET.into());
+        let has = |set: &str| charset.split(',').any(|s| s.trim().eq_ignore_ascii_case(set));
+        let generator = PasswordGenerator::new()
+            .length(env_or("SFSS_PASSWORD_LENGTH", DEFAULT_LENGTH))
+            .lowercase_letters(has("lowercase"))
+            .uppercase_letters(has("uppercase"))
+            .numbers(has("numbers"))
+            .symbols(has("symbols"))
+            .spaces(false);
+        // Fail with a clear message instead of silently not protecting uploads
+        if let Err(e) = generator.generate_one() {
+            panic!("SFSS_PASSWORD_LENGTH or SFSS_PASSWORD_CHARSET is invalid: {}", e);
+        }
+        PasswordPolicy {
+            generator,
+            min_length: env_or("SFSS_PASSWORD_MIN_LENGTH", DEFAULT_LENGTH),
+            min_score: env_or("SFSS_PASSWORD_MIN_SCORE", 0.0),
+        }
+    };
+}
+
+// Reads the settings right away, so invalid ones stop sfss at startup instead
+// of failing the first upload
+pub fn init() {
+    lazy_static::initialize(&POLICY);
+}
+
+pub fn generate() -> Option<String> {
+    POLICY.generator.generate_one().ok()
+}
+
+// Checks a password chosen by the uploader, the error says what is wrong with it
+pub fn check(password: &str) -> Result<(), String> {
+    let length = password.chars().count();
+    if length < POLICY.min_length {
+        return Err(format!(
+            "the password needs at least {} characters",
+            POLICY.min_length
+        ));
+    }
+    if POLICY.min_score > 0.0 && scorer::score(&analyzer::analyze(password)) < POLICY.min_score {
+        return Err("the password is too weak".into());
+    }
+    Ok(())
+}
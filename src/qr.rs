@@ -0,0 +1,50 @@
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+
+use image::{DynamicImage, ImageOutputFormat, Luma};
+use qrcode::render::svg;
+use qrcode::QrCode;
+
+use crate::context::PageContext;
+use crate::utils::percent_encode;
+
+// Size of the rendered code in pixels, including the quiet zone
+const MIN_SIZE: u32 = 256;
+
+// The full link to the file, with the password if there is one
+pub fn share_url(page: &PageContext) -> String {
+    match &page.password {
+        Some(password) => format!(
+            "{}{}/{}?password={}",
+            page.url,
+            page.webroot,
+            page.code,
+            percent_encode(password)
+        ),
+        None => format!("{}{}/{}", page.url, page.webroot, page.code),
+    }
+}
+
+fn encode(data: &str) -> IoResult<QrCode> {
+    QrCode::new(data.as_bytes()).map_err(|e| IoError::new(IoErrorKind::InvalidInput, e))
+}
+
+pub fn svg(data: &str) -> IoResult<String> {
+    Ok(encode(data)?
+        .render::<svg::Color>()
+        .min_dimensions(MIN_SIZE, MIN_SIZE)
+        .build())
+}
+
+pub fn png(data: &str) -> IoResult<Vec<u8>> {
+    let image = encode(data)?
+        .render::<Luma<u8>>()
+        .min_dimensions(MIN_SIZE, MIN_SIZE)
+        .build();
+    let mut content = Vec::new();
+    DynamicImage::ImageLuma8(image)
+        .write_to(&mut content, ImageOutputFormat::Png)
+        .map_err(|e| IoError::new(IoErrorKind::Other, e))?;
+    Ok(content)
+}
@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::Serialize;
+
+use crate::apikeys::ApiKey;
+use crate::sfss_format::SfssFile;
+
+// Storage quotas, for how much every API key, user and anonymous client may
+// have stored at once. Usage is counted from the owner in the headers of the
+// stored files, anonymous uploads are only given an owner of ip:ADDR while an
+// anonymous quota is set. Files also have a size limit by their mime type, on
+// top of the limit for the whole request
+const DEFAULT_REQUEST_LIMIT: u64 = 128 * 1024 * 1024;
+
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    pub bytes: Option<u64>,
+    pub files: Option<u64>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Usage {
+    bytes: u64,
+    files: u64,
+}
+
+// What the quota api answers with
+#[derive(Serialize)]
+pub struct Report {
+    owner: Option<String>,
+    bytes: u64,
+    files: u64,
+    max_bytes: Option<u64>,
+    max_files: Option<u64>,
+}
+
+// A size in bytes, optionally with a binary unit like 10MiB or 10M
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| false == c.is_ascii_digit())
+        .unwrap_or_else(|| s.len());
+    let (number, unit) = s.split_at(split);
+    let factor: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1024,
+        "m" | "mib" => 1024 * 1024,
+        "g" | "gib" => 1024 * 1024 * 1024,
+        "t" | "tib" => 1024 * 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(factor)
+}
+
+fn env_size(var: &str) -> Option<u64> {
+    let value = std::env::var(var).ok()?;
+    let size = parse_size(&value);
+    if size.is_none() {
+        eprintln!("Ignoring {}, {} is not a size", var, value);
+    }
+    size
+}
+
+fn env_count(var: &str) -> Option<u64> {
+    std::env::var(var).ok().and_then(|v| v.trim().parse().ok())
+}
+
+lazy_static::lazy_static! {
+    static ref REQUEST_LIMIT: u64 = env_size("SFSS_MAX_UPLOAD_SIZE").unwrap_or(DEFAULT_REQUEST_LIMIT);
+    // For every API key and user
+    static ref OWNER_LIMITS: Limits = Limits {
+        bytes: env_size("SFSS_QUOTA_BYTES"),
+        files: env_count("SFSS_QUOTA_FILES"),
+    };
+    // For every client uploading without an API key or account
+    static ref ANONYMOUS_LIMITS: Limits = Limits {
+        bytes: env_size("SFSS_ANONYMOUS_QUOTA_BYTES"),
+        files: env_count("SFSS_ANONYMOUS_QUOTA_FILES"),
+    };
+    // Like image/*=10MiB,video/*=1GiB,*=100MiB, the first match counts
+    static ref TYPE_LIMITS: Vec<(String, u64)> = std::env::var("SFSS_MAX_FILE_SIZES")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|limit| false == limit.trim().is_empty())
+        .filter_map(|limit| {
+            let mut split = limit.splitn(2, '=');
+            let mime = split.next()?.trim().to_string();
+            match split.next().and_then(parse_size) {
+                Some(size) => Some((mime, size)),
+                None => {
+                    eprintln!("Ignoring {} in SFSS_MAX_FILE_SIZES, it has no size", limit);
+                    None
+                }
+            }
+        })
+        .collect();
+    // Built by reading every header on first use, then kept up to date by
+    // storing, rewriting and removing files. Expired files count until the
+    // expiry sweep deletes them
+    static ref USAGE: Mutex<Option<HashMap<String, Usage>>> = Mutex::new(None);
+}
+
+// The most a single upload request may send
+pub fn request_limit() -> u64 {
+    *REQUEST_LIMIT
+}
+
+// The largest a file of the mime type may be
+pub fn max_size(mime: &str) -> Option<u64> {
+    TYPE_LIMITS
+        .iter()
+        .find(|(pattern, _)| {
+            pattern == "*"
+                || pattern == mime
+                || (pattern.ends_with("/*") && mime.starts_with(&pattern[..pattern.len() - 1]))
+        })
+        .map(|(_, size)| *size)
+}
+
+// What anonymous uploads from the client are owned by, nothing unless there
+// is a quota for them, so their address isn't stored for no reason
+pub fn anonymous_owner(ip: Option<IpAddr>) -> Option<String> {
+    if ANONYMOUS_LIMITS.bytes.is_none() && ANONYMOUS_LIMITS.files.is_none() {
+        return None;
+    }
+    ip.map(|ip| format!("ip:{}", ip))
+}
+
+// The quota of an owner, API keys can have their own
+pub fn limits(owner: &str, api_key: Option<&ApiKey>) -> Limits {
+    if owner.starts_with("ip:") {
+        return *ANONYMOUS_LIMITS;
+    }
+    let mut limits = *OWNER_LIMITS;
+    if let Some(api_key) = api_key {
+        limits.bytes = api_key.quota_bytes.or(limits.bytes);
+        limits.files = api_key.quota_files.or(limits.files);
+    }
+    limits
+}
+
+fn scan() -> std::io::Result<HashMap<String, Usage>> {
+    let mut res: HashMap<String, Usage> = HashMap::new();
+    for entry in std::fs::read_dir(std::env::var("SFSS_LOCATION").unwrap())? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // Thumbnails, temporary files and the like have an extension, stored files never do
+        if name.contains('.') {
+            continue;
+        }
+        let size = entry.metadata()?.len();
+        if let Some(owner) = SfssFile::stored(name).ok().and_then(|file| file.owner) {
+            let usage = res.entry(owner).or_default();
+            usage.bytes += size;
+            usage.files += 1;
+        }
+    }
+    Ok(res)
+}
+
+// Runs with the usage index, which is built first if it hasn't been yet
+fn with_usage<T>(f: impl FnOnce(&mut HashMap<String, Usage>) -> T) -> T {
+    if USAGE.lock().unwrap_or_else(|e| e.into_inner()).is_none() {
+        // Scanned without holding the lock, so uploads aren't held up by it
+        let scanned = scan().unwrap_or_else(|e| {
+            eprintln!("Error counting the usage of stored files: {:?}", e);
+            HashMap::new()
+        });
+        let mut usage = USAGE.lock().unwrap_or_else(|e| e.into_inner());
+        if usage.is_none() {
+            *usage = Some(scanned);
+        }
+    }
+    let mut usage = USAGE.lock().unwrap_or_else(|e| e.into_inner());
+    f(usage.get_or_insert_with(HashMap::new))
+}
+
+fn usage(owner: &str) -> Usage {
+    with_usage(|usage| usage.get(owner).copied().unwrap_or_default())
+}
+
+fn update(owner: &str, change: impl FnOnce(&mut Usage)) {
+    if let Some(usage) = USAGE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        change(usage.entry(owner.to_string()).or_default());
+    }
+}
+
+// Called when a new file is written
+pub fn added(owner: &str, size: u64) {
+    update(owner, |usage| {
+        usage.bytes += size;
+        usage.files += 1;
+    });
+}
+
+// Called when a file is deleted
+pub fn removed(owner: &str, size: u64) {
+    update(owner, |usage| {
+        usage.bytes = usage.bytes.saturating_sub(size);
+        usage.files = usage.files.saturating_sub(1);
+    });
+}
+
+// Called when a file is written again with a different size
+pub fn resized(owner: &str, old_size: u64, new_size: u64) {
+    update(owner, |usage| {
+        usage.bytes = usage
+            .bytes
+            .saturating_sub(old_size)
+            .saturating_add(new_size);
+    });
+}
+
+// Usage held for an upload while it is being stored, so uploads at the same
+// time can't all fit into the same space. It is given back once dropped, by
+// then SfssFile::store has added what the stored files actually take up
+pub struct Reservation {
+    owner: String,
+    bytes: u64,
+    files: u64,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let (bytes, files) = (self.bytes, self.files);
+        update(&self.owner, |usage| {
+            usage.bytes = usage.bytes.saturating_sub(bytes);
+            usage.files = usage.files.saturating_sub(files);
+        });
+    }
+}
+
+// Reserves room for an upload if the owner can store this much more. Going
+// over the stored bytes is 413 like a file that is too large, going over the
+// amount of files is 429. Sizes are counted before compression, so this errs
+// on the side of the uploader
+pub fn reserve(
+    owner: &str,
+    limits: Limits,
+    bytes: u64,
+    files: u64,
+) -> Result<Option<Reservation>, (Status, String)> {
+    if limits.bytes.is_none() && limits.files.is_none() {
+        return Ok(None);
+    }
+    with_usage(|usage| {
+        let usage = usage.entry(owner.to_string()).or_default();
+        if let Some(max) = limits.bytes {
+            if usage.bytes.saturating_add(bytes) > max {
+                return Err((
+                    Status::PayloadTooLarge,
+                    format!(
+                        "the upload would go over the storage quota of {}, {} is in use",
+                        crate::utils::human_size(max),
+                        crate::utils::human_size(usage.bytes)
+                    ),
+                ));
+            }
+        }
+        if let Some(max) = limits.files {
+            if usage.files.saturating_add(files) > max {
+                return Err((
+                    Status::TooManyRequests,
+                    format!("the upload would go over the quota of {} files", max),
+                ));
+            }
+        }
+        usage.bytes += bytes;
+        usage.files += files;
+        Ok(Some(Reservation {
+            owner: owner.to_string(),
+            bytes,
+            files,
+        }))
+    })
+}
+
+// The owner and quota of the caller, for GET /upload/api/quota
+pub struct Caller {
+    owner: Option<String>,
+    limits: Limits,
+}
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for Caller {
+    type Error = ();
+
+    async fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let api_key = match crate::apikeys::from_request(req) {
+            Ok(api_key) => api_key,
+            Err(_) => return Outcome::Failure((Status::Unauthorized, ())),
+        };
+        let owner = match (&api_key, crate::accounts::session_user(req)) {
+            (Some(api_key), _) => Some(api_key.owner()),
+            (None, Some(user)) => Some(crate::accounts::owner(&user)),
+            (None, None) => anonymous_owner(crate::ratelimit::client_ip(req)),
+        };
+        let limits = owner
+            .as_deref()
+            .map_or_else(Limits::default, |owner| limits(owner, api_key.as_ref()));
+        Outcome::Success(Caller { owner, limits })
+    }
+}
+
+impl Caller {
+    pub fn report(self) -> Report {
+        let usage = self.owner.as_deref().map_or_else(Usage::default, usage);
+        Report {
+            owner: self.owner,
+            bytes: usage.bytes,
+            files: usage.files,
+            max_bytes: self.limits.bytes,
+            max_files: self.limits.files,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1234"), Some(1234));
+        assert_eq!(parse_size(" 10b "), Some(10));
+        assert_eq!(parse_size("10K"), Some(10 * 1024));
+        assert_eq!(parse_size("10 MiB"), Some(10 * 1024 * 1024));
+        assert_eq!(parse_size("2gib"), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("1T"), Some(1024 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("MiB"), None);
+        assert_eq!(parse_size("1.5M"), None);
+        assert_eq!(parse_size("-1"), None);
+        assert_eq!(parse_size("10MB"), None);
+        assert_eq!(parse_size("99999999999T"), None);
+    }
+}
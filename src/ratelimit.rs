@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::Response;
+
+// Rate limits per client, with a token bucket for every client and kind of
+// request. A bucket holds as many requests as the limit allows per period and
+// refills evenly over it, so short bursts are fine but floods get 429 with a
+// Retry-After header. Clients are told apart by their address, which is only
+// taken from X-Forwarded-For or X-Real-IP when sent by a trusted proxy
+const DEFAULT_UPLOADS: &str = "30/min";
+const DEFAULT_DOWNLOADS: &str = "300/min";
+const DEFAULT_PASSWORDS: &str = "10/min";
+const DEFAULT_TRUSTED_PROXIES: &str = "127.0.0.0/8,::1";
+// Full buckets are only cleaned up once there are this many
+const MAX_ENTRIES: usize = 10_000;
+
+struct Rate {
+    capacity: f64,
+    per_second: f64,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+pub struct Limiter {
+    rate: Option<Rate>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+// An address, or a network like 10.0.0.0/8
+struct Network {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+// Like 30/min, 5/s or 1000/h, off or 0 disables the limit
+fn parse_rate(var: &str, default: &str) -> Option<Rate> {
+    let value = std::env::var(var).unwrap_or_else(|_| default.to_string());
+    let value = value.trim();
+    if value == "off" || value == "0" {
+        return None;
+    }
+    let mut split = value.splitn(2, '/');
+    let count = split.next().and_then(|c| c.trim().parse::<u32>().ok());
+    let seconds = match split.next().map(str::trim) {
+        Some("s") | Some("sec") | Some("second") => Some(1),
+        Some("m") | Some("min") | Some("minute") => Some(60),
+        Some("h") | Some("hour") => Some(60 * 60),
+        Some("d") | Some("day") => Some(24 * 60 * 60),
+        _ => None,
+    };
+    match (count, seconds) {
+        (Some(count), Some(seconds)) if count > 0 => Some(Rate {
+            capacity: count as f64,
+            per_second: count as f64 / seconds as f64,
+        }),
+        _ => {
+            eprintln!("Ignoring {}, {} is not a rate like 30/min", var, value);
+            None
+        }
+    }
+}
+
+fn parse_network(s: &str) -> Option<Network> {
+    let mut split = s.trim().splitn(2, '/');
+    let addr: IpAddr = split.next()?.parse().ok()?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match split.next() {
+        Some(prefix) => prefix.parse().ok().filter(|p| *p <= max)?,
+        None => max,
+    };
+    Some(Network { addr, prefix })
+}
+
+fn to_bits(addr: IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(v4) => u32::from(v4) as u128,
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+impl Network {
+    fn contains(&self, addr: IpAddr) -> bool {
+        // IPv4 clients of a dual stack socket show up as ::ffff:a.b.c.d
+        let addr = match addr {
+            IpAddr::V6(v6) => v6
+                .to_ipv4()
+                .filter(|_| self.addr.is_ipv4())
+                .map_or(addr, IpAddr::V4),
+            addr => addr,
+        };
+        if addr.is_ipv4() != self.addr.is_ipv4() {
+            return false;
+        }
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let shift = bits - self.prefix;
+        shift == bits || to_bits(addr) >> shift == to_bits(self.addr) >> shift
+    }
+}
+
+impl Limiter {
+    fn new(var: &str, default: &str) -> Self {
+        Limiter {
+            rate: parse_rate(var, default),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // How long until the client has a token again, without taking one
+    fn wait(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let rate = self.rate.as_ref()?;
+        let buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let bucket = buckets.get(&ip)?;
+        let tokens =
+            bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * rate.per_second;
+        if tokens >= 1.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64((1.0 - tokens) / rate.per_second))
+    }
+
+    // Takes a token from the bucket of the client, or tells how long until there is one
+    fn take(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let rate = match &self.rate {
+            Some(rate) => rate,
+            None => return Ok(()),
+        };
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= MAX_ENTRIES {
+            buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.last).as_secs_f64() * rate.per_second
+                    < rate.capacity
+            });
+        }
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: rate.capacity,
+            last: now,
+        });
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.last).as_secs_f64() * rate.per_second)
+            .min(rate.capacity);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - bucket.tokens) / rate.per_second,
+        ))
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref UPLOADS: Limiter = Limiter::new("SFSS_RATE_UPLOADS", DEFAULT_UPLOADS);
+    static ref DOWNLOADS: Limiter = Limiter::new("SFSS_RATE_DOWNLOADS", DEFAULT_DOWNLOADS);
+    static ref PASSWORDS: Limiter = Limiter::new("SFSS_RATE_PASSWORDS", DEFAULT_PASSWORDS);
+    static ref TRUSTED_PROXIES: Vec<Network> = std::env::var("SFSS_TRUSTED_PROXIES")
+        .unwrap_or_else(|_| DEFAULT_TRUSTED_PROXIES.to_string())
+        .split(',')
+        .filter(|proxy| false == proxy.trim().is_empty())
+        .filter_map(|proxy| {
+            let network = parse_network(proxy);
+            if network.is_none() {
+                eprintln!("Ignoring {} in SFSS_TRUSTED_PROXIES, it is not an address or network", proxy);
+            }
+            network
+        })
+        .collect();
+}
+
+// The address of the client. Requests from a trusted proxy are followed back
+// through X-Forwarded-For to the first address that isn't a trusted proxy,
+// anything before that could have been made up by the client
+pub fn client_ip(req: &Request<'_>) -> Option<IpAddr> {
+    let remote = req.remote()?.ip();
+    Some(forwarded_client(
+        remote,
+        req.headers().get("X-Forwarded-For"),
+        req.headers().get_one("X-Real-IP"),
+        &TRUSTED_PROXIES,
+    ))
+}
+
+fn forwarded_client<'h>(
+    remote: IpAddr,
+    forwarded_for: impl Iterator<Item = &'h str>,
+    real_ip: Option<&str>,
+    trusted: &[Network],
+) -> IpAddr {
+    let is_trusted = |addr: IpAddr| trusted.iter().any(|proxy| proxy.contains(addr));
+    if false == is_trusted(remote) {
+        return remote;
+    }
+    let forwarded: Vec<IpAddr> = forwarded_for
+        .flat_map(|header| header.split(','))
+        .filter_map(|addr| addr.trim().parse().ok())
+        .collect();
+    if let Some(first) = forwarded.first() {
+        return forwarded
+            .iter()
+            .rev()
+            .copied()
+            .find(|addr| false == is_trusted(*addr))
+            .unwrap_or(*first);
+    }
+    real_ip
+        .and_then(|addr| addr.trim().parse().ok())
+        .unwrap_or(remote)
+}
+
+// Password attempts are limited where the password is checked, since it can
+// come with any request for a protected file. Only failed attempts use up a
+// token, a client that is out of them can't try any password though
+pub fn password_wait(ip: IpAddr) -> Option<Duration> {
+    PASSWORDS.wait(ip, Instant::now())
+}
+
+pub fn password_failed(ip: IpAddr) {
+    // Already out of tokens if this fails, which password_wait tells next time
+    let _ = PASSWORDS.take(ip, Instant::now());
+}
+
+// How long a limited client has to wait, kept for the Retry-After header
+struct RetryAfter(Option<u64>);
+
+pub trait Kind: Send + Sync + 'static {
+    fn limiter() -> &'static Limiter;
+}
+
+pub struct Uploads;
+pub struct Downloads;
+
+impl Kind for Uploads {
+    fn limiter() -> &'static Limiter {
+        &UPLOADS
+    }
+}
+
+impl Kind for Downloads {
+    fn limiter() -> &'static Limiter {
+        &DOWNLOADS
+    }
+}
+
+// Fails with 429 once the client used up its requests of the kind. Checked
+// before the body of a request is read
+pub struct RateLimit<K>(PhantomData<K>);
+
+#[rocket::async_trait]
+impl<'a, 'r, K: Kind> FromRequest<'a, 'r> for RateLimit<K> {
+    type Error = ();
+
+    async fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let ip = match client_ip(req) {
+            Some(ip) => ip,
+            None => return Outcome::Success(RateLimit(PhantomData)),
+        };
+        match K::limiter().take(ip, Instant::now()) {
+            Ok(()) => Outcome::Success(RateLimit(PhantomData)),
+            Err(wait) => {
+                // Rounded up, waiting less than the header says would still be limited
+                let secs = wait.as_secs() + (wait.subsec_nanos() > 0) as u64;
+                req.local_cache(|| RetryAfter(Some(secs)));
+                Outcome::Failure((Status::TooManyRequests, ()))
+            }
+        }
+    }
+}
+
+// Adds Retry-After to responses with 429, for rate limits and for password lockouts
+pub fn retry_after(req: &Request<'_>, res: &mut Response<'_>) {
+    if res.status() != Status::TooManyRequests || res.headers().contains("Retry-After") {
+        return;
+    }
+    let ip = client_ip(req);
+    let secs = req.local_cache(|| RetryAfter(None)).0.or_else(|| {
+        crate::attempts::locked_for(ip)
+            .or_else(|| ip.and_then(password_wait))
+            .map(|wait| wait.as_secs() + 1)
+    });
+    if let Some(secs) = secs {
+        res.set_raw_header("Retry-After", secs.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn network(s: &str) -> Network {
+        parse_network(s).unwrap()
+    }
+
+    #[test]
+    fn networks() {
+        let lan = network("10.1.0.0/16");
+        assert!(lan.contains(ip("10.1.2.3")));
+        assert!(false == lan.contains(ip("10.2.0.1")));
+        assert!(lan.contains(ip("::ffff:10.1.2.3")));
+        assert!(false == lan.contains(ip("fd00::1")));
+
+        let single = network("192.168.0.1");
+        assert!(single.contains(ip("192.168.0.1")));
+        assert!(false == single.contains(ip("192.168.0.2")));
+
+        let v6 = network("fd00::/8");
+        assert!(v6.contains(ip("fd12::1")));
+        assert!(false == v6.contains(ip("fe80::1")));
+        assert!(false == v6.contains(ip("10.0.0.1")));
+
+        assert!(network("0.0.0.0/0").contains(ip("8.8.8.8")));
+        assert!(network("::/0").contains(ip("2001:db8::1")));
+
+        assert!(parse_network("10.0.0.0/33").is_none());
+        assert!(parse_network("::/129").is_none());
+        assert!(parse_network("localhost").is_none());
+    }
+
+    #[test]
+    fn forwarded_clients() {
+        let trusted = vec![network("127.0.0.0/8"), network("10.0.0.0/8")];
+        let client = |remote: &str, forwarded_for: &[&str], real_ip: Option<&str>| {
+            forwarded_client(ip(remote), forwarded_for.iter().copied(), real_ip, &trusted)
+        };
+        // Headers from untrusted clients are ignored
+        assert_eq!(
+            client("1.2.3.4", &["5.6.7.8"], Some("5.6.7.8")),
+            ip("1.2.3.4")
+        );
+        assert_eq!(client("127.0.0.1", &["5.6.7.8"], None), ip("5.6.7.8"));
+        // Only the part added by trusted proxies is believed
+        assert_eq!(
+            client("127.0.0.1", &["6.6.6.6, 5.6.7.8", "10.0.0.2"], None),
+            ip("5.6.7.8")
+        );
+        // Everything went through trusted proxies
+        assert_eq!(
+            client("127.0.0.1", &["10.0.0.3, 10.0.0.2"], None),
+            ip("10.0.0.3")
+        );
+        assert_eq!(
+            client("127.0.0.1", &["garbage"], Some(" 5.6.7.8 ")),
+            ip("5.6.7.8")
+        );
+        assert_eq!(client("127.0.0.1", &[], Some("garbage")), ip("127.0.0.1"));
+    }
+
+    #[test]
+    fn buckets() {
+        let limiter = Limiter {
+            rate: Some(Rate {
+                capacity: 2.0,
+                per_second: 1.0,
+            }),
+            buckets: Mutex::new(HashMap::new()),
+        };
+        let (client, now) = (ip("1.2.3.4"), Instant::now());
+        assert_eq!(limiter.wait(client, now), None);
+        assert_eq!(limiter.take(client, now), Ok(()));
+        assert_eq!(limiter.take(client, now), Ok(()));
+        assert_eq!(limiter.take(client, now), Err(Duration::from_secs(1)));
+        assert_eq!(limiter.wait(client, now), Some(Duration::from_secs(1)));
+        // Other clients have their own bucket
+        assert_eq!(limiter.take(ip("1.2.3.5"), now), Ok(()));
+        // Refilled over time, but never above the capacity
+        let later = now + Duration::from_secs(10);
+        assert_eq!(limiter.wait(client, later), None);
+        assert_eq!(limiter.take(client, later), Ok(()));
+        assert_eq!(limiter.take(client, later), Ok(()));
+        assert!(limiter.take(client, later).is_err());
+    }
+
+    #[test]
+    fn rates() {
+        let per_second = |value| parse_rate("SFSS_TEST_UNSET_RATE", value).map(|r| r.per_second);
+        let rate = parse_rate("SFSS_TEST_UNSET_RATE", "30/min").unwrap();
+        assert!((rate.capacity - 30.0).abs() < f64::EPSILON);
+        assert!((rate.per_second - 0.5).abs() < f64::EPSILON);
+        assert!((per_second("5 / s").unwrap() - 5.0).abs() < f64::EPSILON);
+        assert!(per_second("off").is_none());
+        assert!(per_second("0/min").is_none());
+        assert!(per_second("30/week").is_none());
+        assert!(per_second("many/min").is_none());
+    }
+}
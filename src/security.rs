@@ -0,0 +1,87 @@
+use rocket::http::ContentType;
+use rocket::Request;
+
+// Policy for anything uploaded by users, nothing is allowed to run and the
+// document gets a unique origin so it can't touch the rest of the site.
+// Media may also come from the user content origin, for pages embedding files
+fn user_content_csp() -> String {
+    let content_origin = crate::APP_CONTEXT.content_url.as_deref().unwrap_or("");
+    format!(
+        "default-src 'none'; img-src 'self' {0} data:; media-src 'self' {0}; style-src 'self' 'unsafe-inline'; sandbox",
+        content_origin
+    )
+}
+// Media pages only embed the raw file, they can't be sandboxed since the
+// embedded PDF viewer would inherit the sandbox
+pub fn media_page_csp() -> String {
+    let content_origin = crate::APP_CONTEXT.content_url.as_deref().unwrap_or("");
+    format!(
+        "default-src 'none'; img-src 'self' {0}; media-src 'self' {0}; frame-src 'self' {0}; style-src 'self'",
+        content_origin
+    )
+}
+// The viewer for encrypted files runs our own script, which fetches the
+// ciphertext, possibly from the user content origin
+pub fn encrypted_page_csp() -> String {
+    let content_origin = crate::APP_CONTEXT.content_url.as_deref().unwrap_or("");
+    format!(
+        "default-src 'none'; script-src 'self'; connect-src 'self' {0}; style-src 'self'",
+        content_origin
+    )
+}
+// Chrome refuses to render PDFs in a sandboxed document, the viewer is
+// sandboxed on its own anyway
+pub const PDF_CSP: &str = "default-src 'none'; object-src 'self'";
+
+// Only these types are ever served inline, everything else is sent as an
+// attachment with a generic binary content type
+const INLINE_TYPES: &[&str] = &[
+    "text/plain",
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/bmp",
+    "image/x-icon",
+    "image/avif",
+    "application/pdf",
+    "audio/mpeg",
+    "audio/ogg",
+    "audio/flac",
+    "audio/wav",
+    "audio/mp4",
+    "video/mp4",
+    "video/webm",
+];
+
+pub fn is_inline_safe(content_type: &ContentType) -> bool {
+    let essence = format!("{}/{}", content_type.top(), content_type.sub()).to_ascii_lowercase();
+    INLINE_TYPES.contains(&essence.as_str())
+}
+
+pub fn csp_for(content_type: &ContentType) -> String {
+    if *content_type == ContentType::PDF {
+        PDF_CSP.to_string()
+    } else {
+        user_content_csp()
+    }
+}
+
+// Routes that are meant to be used from other sites
+pub fn allows_cors(path: &str) -> bool {
+    path.ends_with("/raw") || path.starts_with("/upload/api") || path.starts_with("/languages")
+}
+
+// Whether the request was made to the separate user content origin
+pub fn is_content_host(req: &Request<'_>, content_url: &str) -> bool {
+    let content_host = content_url
+        .splitn(2, "://")
+        .last()
+        .unwrap_or(content_url)
+        .split('/')
+        .next()
+        .unwrap_or("");
+    req.headers()
+        .get_one("Host")
+        .map_or(false, |host| host.eq_ignore_ascii_case(content_host))
+}
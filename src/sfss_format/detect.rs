@@ -0,0 +1,439 @@
+// Language detection for uploads that didn't specify a language.
+// Every function here returns one of the display names from the
+// highlightjs-rs language list (see /languages), which then gets resolved
+// to an id with `highlightjs_rs::exact` and `highlightjs_rs::to_id`.
+
+// How many lines at the start and end of a file are searched for modelines
+const MODELINE_LINES: usize = 5;
+// Only the start of the content is looked at by the heuristics
+const SNIFF_LEN: usize = 8192;
+
+pub fn detect_language(filename: &str, content: &[u8]) -> Option<usize> {
+    let name = detect_name(filename, content)?;
+    highlightjs_rs::exact(name).and_then(|m| highlightjs_rs::to_id(m))
+}
+
+fn detect_name(filename: &str, content: &[u8]) -> Option<&'static str> {
+    let truncated = content.len() > SNIFF_LEN;
+    let head = String::from_utf8_lossy(&content[..content.len().min(SNIFF_LEN)]);
+    // Modelines can be at the very end, past the part the heuristics look at
+    let tail = String::from_utf8_lossy(&content[content.len().saturating_sub(SNIFF_LEN)..]);
+    from_filename(filename)
+        .or_else(|| from_shebang(&head))
+        .or_else(|| from_modeline(&head, &tail))
+        .or_else(|| from_content(&head, truncated))
+}
+
+fn from_filename(filename: &str) -> Option<&'static str> {
+    let basename = filename.rsplit('/').next().unwrap_or(filename);
+    let by_name = match basename {
+        "Dockerfile" | "Containerfile" => Some("Dockerfile"),
+        "Makefile" | "makefile" | "GNUmakefile" => Some("Makefile"),
+        "CMakeLists.txt" => Some("CMake"),
+        "Cargo.lock" | "Pipfile" => Some("TOML"),
+        ".bashrc" | ".bash_profile" | ".profile" | ".zshrc" => Some("Bash"),
+        ".vimrc" => Some("VimL"),
+        "nginx.conf" => Some("Nginx config"),
+        ".htaccess" | "httpd.conf" => Some("Apache config"),
+        _ => None,
+    };
+    if by_name.is_some() {
+        return by_name;
+    }
+
+    let ext = &basename[basename.rfind('.')? + 1..];
+    match ext.to_ascii_lowercase().as_str() {
+        "sh" | "bash" | "zsh" => Some("Bash"),
+        "c" | "h" => Some("C"),
+        "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => Some("C++"),
+        "cs" => Some("C#"),
+        "clj" | "cljs" | "edn" => Some("Clojure"),
+        "coffee" => Some("CoffeeScript"),
+        "cr" => Some("Crystal"),
+        "css" => Some("CSS"),
+        "d" => Some("D"),
+        "dart" => Some("Dart"),
+        "diff" | "patch" => Some("Diff"),
+        "ex" | "exs" => Some("Elixir"),
+        "elm" => Some("Elm"),
+        "erl" | "hrl" => Some("Erlang"),
+        "fs" | "fsx" => Some("F#"),
+        "glsl" | "vert" | "frag" => Some("GLSL"),
+        "go" => Some("Go"),
+        "gradle" => Some("Gradle"),
+        "groovy" => Some("Groovy"),
+        "haml" => Some("HAML"),
+        "hbs" | "handlebars" => Some("Handlebars"),
+        "hs" => Some("Haskell"),
+        "hx" => Some("Haxe"),
+        "html" | "htm" | "xhtml" => Some("HTML"),
+        "ini" | "cfg" => Some("INI"),
+        "java" => Some("Java"),
+        "js" | "mjs" | "cjs" | "jsx" => Some("JavaScript"),
+        "json" => Some("JSON"),
+        "jl" => Some("Julia"),
+        "kt" | "kts" => Some("Kotlin"),
+        "tex" => Some("LaTeX"),
+        "less" => Some("Less"),
+        "lisp" | "el" => Some("Lisp"),
+        "lua" => Some("Lua"),
+        "md" | "markdown" => Some("Markdown"),
+        "m" => Some("Objective-C"),
+        "nim" => Some("Nim"),
+        "nix" => Some("Nix"),
+        "ml" | "mli" => Some("OCaml"),
+        "scad" => Some("OpenSCAD"),
+        "php" => Some("PHP"),
+        "pl" | "pm" => Some("Perl"),
+        "ps1" | "psm1" => Some("PowerShell"),
+        "pde" => Some("Processing"),
+        "pp" => Some("Puppet"),
+        "py" | "pyw" => Some("Python"),
+        "r" => Some("R"),
+        "re" | "rei" => Some("ReasonML"),
+        "rb" => Some("Ruby"),
+        "rs" => Some("Rust"),
+        "scala" | "sc" => Some("Scala"),
+        "scm" | "ss" => Some("Scheme"),
+        "scss" => Some("SCSS"),
+        "sql" => Some("SQL"),
+        "swift" => Some("Swift"),
+        "toml" => Some("TOML"),
+        "ts" | "tsx" => Some("TypeScript"),
+        "twig" => Some("Twig"),
+        "vb" => Some("VB.NET"),
+        "vbs" => Some("VBScript"),
+        "vim" => Some("VimL"),
+        "xml" | "svg" | "plist" | "xsd" => Some("XML"),
+        "yml" | "yaml" => Some("YAML"),
+        _ => None,
+    }
+}
+
+fn from_interpreter(interpreter: &str) -> Option<&'static str> {
+    // Strip version suffixes, python3.9 -> python
+    let interpreter = interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    match interpreter {
+        "sh" | "bash" | "zsh" | "dash" | "ksh" => Some("Bash"),
+        "python" | "pypy" => Some("Python"),
+        "node" | "nodejs" => Some("JavaScript"),
+        "deno" | "ts-node" => Some("TypeScript"),
+        "ruby" => Some("Ruby"),
+        "perl" => Some("Perl"),
+        "php" => Some("PHP"),
+        "lua" | "luajit" => Some("Lua"),
+        "awk" | "gawk" => Some("Awk"),
+        "Rscript" => Some("R"),
+        "julia" => Some("Julia"),
+        "pwsh" => Some("PowerShell"),
+        "elixir" => Some("Elixir"),
+        _ => None,
+    }
+}
+
+fn from_shebang(content: &str) -> Option<&'static str> {
+    let line = content.lines().next()?.strip_prefix("#!")?;
+    let mut parts = line.split_whitespace();
+    let mut interpreter = parts.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        // Skip over env flags like -S
+        interpreter = parts.find(|p| false == p.starts_with('-'))?;
+    }
+    from_interpreter(interpreter)
+}
+
+fn from_modeline(head: &str, tail: &str) -> Option<&'static str> {
+    let tail: Vec<&str> = tail.lines().collect();
+    head.lines()
+        .take(MODELINE_LINES)
+        .chain(
+            tail[tail.len().saturating_sub(MODELINE_LINES)..]
+                .iter()
+                .copied(),
+        )
+        .find_map(|line| vim_modeline(line).or_else(|| emacs_modeline(line)))
+        .and_then(|name| modeline_language(&name))
+}
+
+// Matches `vim: set ft=rust:`, `vi: filetype=python` and `vim: syntax=sh`
+fn vim_modeline(line: &str) -> Option<String> {
+    let idx = ["vim:", "vi:", "ex:"]
+        .iter()
+        .find_map(|m| line.find(m).map(|i| i + m.len()))?;
+    line[idx..]
+        .split(|c: char| c.is_whitespace() || c == ':')
+        .find_map(|opt| {
+            let idx = opt.find('=')?;
+            let (key, value) = (&opt[..idx], &opt[idx + 1..]);
+            match key {
+                "ft" | "filetype" | "syn" | "syntax" => Some(value.to_ascii_lowercase()),
+                _ => None,
+            }
+        })
+}
+
+// Matches `-*- mode: rust -*-` and `-*- python -*-`
+fn emacs_modeline(line: &str) -> Option<String> {
+    let start = line.find("-*-")? + 3;
+    let end = line[start..].find("-*-")? + start;
+    let inner = line[start..end].trim();
+    if false == inner.contains(':') {
+        return Some(inner.to_ascii_lowercase());
+    }
+    inner.split(';').find_map(|opt| {
+        let idx = opt.find(':')?;
+        let (key, value) = (&opt[..idx], &opt[idx + 1..]);
+        if key.trim().eq_ignore_ascii_case("mode") {
+            Some(value.trim().to_ascii_lowercase())
+        } else {
+            None
+        }
+    })
+}
+
+fn modeline_language(name: &str) -> Option<&'static str> {
+    let name = name.trim_end_matches("-mode");
+    from_interpreter(name).or_else(|| match name {
+        "rust" => Some("Rust"),
+        "c" => Some("C"),
+        "cpp" | "c++" => Some("C++"),
+        "cs" | "csharp" => Some("C#"),
+        "go" => Some("Go"),
+        "java" => Some("Java"),
+        "javascript" | "js" => Some("JavaScript"),
+        "typescript" | "ts" => Some("TypeScript"),
+        "json" => Some("JSON"),
+        "yaml" => Some("YAML"),
+        "toml" => Some("TOML"),
+        "html" => Some("HTML"),
+        "xml" => Some("XML"),
+        "css" => Some("CSS"),
+        "markdown" | "md" => Some("Markdown"),
+        "haskell" => Some("Haskell"),
+        "make" | "makefile" => Some("Makefile"),
+        "dockerfile" => Some("Dockerfile"),
+        "sql" => Some("SQL"),
+        "vim" => Some("VimL"),
+        "lisp" | "emacs-lisp" => Some("Lisp"),
+        "nix" => Some("Nix"),
+        "diff" => Some("Diff"),
+        _ => None,
+    })
+}
+
+// Truncated content is only the start of the file
+fn from_content(content: &str, truncated: bool) -> Option<&'static str> {
+    let trimmed = content.trim_start();
+    if trimmed.is_empty() {
+        return None;
+    }
+
+    // Formats that can be recognized from how they start
+    if trimmed.starts_with("<?php") {
+        return Some("PHP");
+    }
+    if trimmed.starts_with("<?xml") {
+        return Some("XML");
+    }
+    let lower_start = trimmed
+        .chars()
+        .take(15)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    if lower_start.starts_with("<!doctype html") || lower_start.starts_with("<html") {
+        return Some("HTML");
+    }
+    if trimmed.starts_with("diff --git")
+        || (trimmed.starts_with("--- ") && content.contains("\n+++ "))
+    {
+        return Some("Diff");
+    }
+    if trimmed.starts_with('{') || trimmed.starts_with('[') {
+        match serde_json::from_str::<serde_json::Value>(content) {
+            Ok(_) => return Some("JSON"),
+            // Valid as far as it goes
+            Err(e) if truncated && e.is_eof() => return Some("JSON"),
+            Err(_) => (),
+        }
+    }
+
+    // Otherwise score the content by keywords typical for each language,
+    // the first entry with the highest score wins
+    let rules: &[(&str, &[&str])] = &[
+        (
+            "Rust",
+            &[
+                "fn main()",
+                "let mut ",
+                "impl ",
+                "pub fn ",
+                "use std::",
+                "::new(",
+            ],
+        ),
+        ("Go", &["package main", "func ", ":= ", "import (", "fmt."]),
+        (
+            "Python",
+            &["def ", "import ", "elif ", "self.", "__name__", "print("],
+        ),
+        (
+            "C++",
+            &[
+                "#include <iostream>",
+                "std::",
+                "namespace ",
+                "template<",
+                "cout <<",
+            ],
+        ),
+        (
+            "C",
+            &[
+                "#include <stdio.h>",
+                "#include",
+                "int main(",
+                "printf(",
+                "malloc(",
+            ],
+        ),
+        (
+            "Java",
+            &[
+                "public class ",
+                "public static void main",
+                "System.out.",
+                "import java.",
+            ],
+        ),
+        (
+            "C#",
+            &[
+                "using System",
+                "namespace ",
+                "Console.Write",
+                "public class ",
+            ],
+        ),
+        (
+            "JavaScript",
+            &["function ", "const ", "=> ", "console.log(", "require("],
+        ),
+        (
+            "TypeScript",
+            &["interface ", ": string", ": number", "export type "],
+        ),
+        ("Ruby", &["def ", "end\n", "puts ", "require '", ".each do"]),
+        (
+            "Bash",
+            &["echo ", "fi\n", "then\n", "esac", "$(", "export "],
+        ),
+        (
+            "SQL",
+            &[
+                "SELECT ",
+                "FROM ",
+                "WHERE ",
+                "INSERT INTO ",
+                "CREATE TABLE ",
+            ],
+        ),
+        ("YAML", &["---\n", ":\n  ", "\n- "]),
+        ("INI", &["\n[", "]\n"]),
+        ("Markdown", &["\n# ", "\n## ", "```", "](http"]),
+    ];
+    let (name, score) = rules
+        .iter()
+        .map(|(name, needles)| {
+            (
+                *name,
+                needles.iter().filter(|n| content.contains(*n)).count(),
+            )
+        })
+        .fold(("", 0), |best, cur| if cur.1 > best.1 { cur } else { best });
+    // A single matching keyword is too weak of a signal
+    if score >= 2 {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modeline(content: &str) -> Option<&'static str> {
+        from_modeline(content, content)
+    }
+
+    #[test]
+    fn filename() {
+        assert_eq!(from_filename("src/main.rs"), Some("Rust"));
+        assert_eq!(from_filename("SCRIPT.PY"), Some("Python"));
+        assert_eq!(from_filename("docker/Dockerfile"), Some("Dockerfile"));
+        assert_eq!(from_filename("README"), None);
+        assert_eq!(from_filename("notes.unknown"), None);
+    }
+
+    #[test]
+    fn shebang() {
+        assert_eq!(from_shebang("#!/bin/bash\necho hi"), Some("Bash"));
+        assert_eq!(
+            from_shebang("#!/usr/bin/env -S python3.9 -u\n"),
+            Some("Python")
+        );
+        assert_eq!(from_shebang("#!/usr/bin/env\n"), None);
+        assert_eq!(from_shebang("echo hi\n#!/bin/bash"), None);
+    }
+
+    #[test]
+    fn modelines() {
+        assert_eq!(modeline("// vim: set ft=rust:\nfn x() {}"), Some("Rust"));
+        assert_eq!(modeline("# -*- mode: python -*-\n"), Some("Python"));
+        assert_eq!(modeline("/* -*- c++ -*- */\n"), Some("C++"));
+        // Modelines are also found at the end, but not in the middle
+        let mut lines = vec!["x"; 12];
+        lines[11] = "# vi: filetype=sh";
+        assert_eq!(modeline(&lines.join("\n")), Some("Bash"));
+        lines[11] = "x";
+        lines[6] = "# vi: filetype=sh";
+        assert_eq!(modeline(&lines.join("\n")), None);
+    }
+
+    #[test]
+    fn content() {
+        assert_eq!(from_content("  <?php echo 1;", false), Some("PHP"));
+        assert_eq!(from_content("<!DOCTYPE html>\n<p>", false), Some("HTML"));
+        assert_eq!(from_content("{\"a\": [1, 2]}", false), Some("JSON"));
+        assert_eq!(from_content("{ not json", false), None);
+        assert_eq!(
+            from_content("diff --git a/x b/x\n--- a/x\n+++ b/x\n", false),
+            Some("Diff")
+        );
+        assert_eq!(
+            from_content(
+                "use std::io;\n\nfn main() {\n    let mut x = 1;\n}\n",
+                false
+            ),
+            Some("Rust")
+        );
+        // One keyword isn't enough
+        assert_eq!(from_content("We should import the photos", false), None);
+        assert_eq!(from_content("   \n", false), None);
+    }
+
+    #[test]
+    fn large_files() {
+        let code = "    let x = 1;\n".repeat(1000);
+        assert!(code.len() > SNIFF_LEN);
+        let content = format!("{}// vim: set ft=rust:\n", code);
+        assert_eq!(detect_name("", content.as_bytes()), Some("Rust"));
+
+        let json = format!("[{}1]", "{\"key\": \"value\"}, ".repeat(1000));
+        assert_eq!(detect_name("", json.as_bytes()), Some("JSON"));
+        // Truncated content still has to be valid as far as it goes
+        let broken = format!("[1, 2}}{}", " ".repeat(SNIFF_LEN));
+        assert_eq!(detect_name("", broken.as_bytes()), None);
+        assert_eq!(from_content("[1, 2}", true), None);
+    }
+}
@@ -4,6 +4,8 @@ pub struct FileFlags {
     pub public: bool,
     pub protected: bool,
     pub no_preview: bool,
+    // Encrypted in the browser, the server only ever sees the ciphertext
+    pub encrypted: bool,
 }
 
 impl Default for FileFlags {
@@ -12,6 +14,7 @@ impl Default for FileFlags {
             public: true,
             protected: false,
             no_preview: false,
+            encrypted: false,
         }
     }
 }
@@ -22,6 +25,7 @@ impl FileFlags {
             public: iter.next().unwrap_or(false),
             protected: iter.next().unwrap_or(false),
             no_preview: iter.next().unwrap_or(false),
+            encrypted: iter.next().unwrap_or(false),
         }
     }
 }
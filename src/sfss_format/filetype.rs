@@ -6,6 +6,10 @@ pub enum FileType {
     Binary(BinaryType),
     // The string is for specifying the language, to be used for syntax highlighting
     Code(u32),
+    // Rendered to html, with the code blocks highlighted
+    Markdown,
+    // A list of other files uploaded together
+    Bundle,
 }
 impl Default for FileType {
     fn default() -> Self {
@@ -26,11 +30,15 @@ impl FileType {
                 LittleEndian::write_u24(&mut res[1..], *id);
                 res
             }
+            Self::Markdown => [3, 0, 0, 0],
+            Self::Bundle => [4, 0, 0, 0],
         }
     }
 
     pub fn from_bytes(b: [u8; 4]) -> Self {
         match b[0] {
+            4 => Self::Bundle,
+            3 => Self::Markdown,
             2 => Self::Code(LittleEndian::read_u24(&b[1..])),
             1 => match b[3] {
                 0 => Self::Binary(BinaryType::Previewable),
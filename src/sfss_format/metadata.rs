@@ -0,0 +1,312 @@
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+pub fn is_supported(mime: &str) -> bool {
+    mime == "image/jpeg" || mime == "image/png" || mime == "image/webp"
+}
+
+// Removes EXIF, XMP and IPTC metadata from images without re-encoding them.
+// Returns None if the image couldn't be parsed, or the type isn't supported
+pub fn strip(mime: &str, content: &[u8]) -> Option<Vec<u8>> {
+    match mime {
+        "image/jpeg" => strip_jpeg(content),
+        "image/png" => strip_png(content),
+        "image/webp" => strip_webp(content),
+        _ => None,
+    }
+}
+
+const EXIF_ORIENTATION: u16 = 0x0112;
+
+// Finds the orientation tag in an EXIF block, it is kept since phones rely
+// on it instead of rotating the actual pixels
+fn exif_orientation(exif: &[u8]) -> Option<(bool, u16)> {
+    if false == exif.starts_with(b"Exif\0\0") {
+        return None;
+    }
+    let tiff = &exif[6..];
+    let little = match tiff.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let read_u16 = |b: &[u8]| {
+        if little {
+            LittleEndian::read_u16(b)
+        } else {
+            BigEndian::read_u16(b)
+        }
+    };
+    let read_u32 = |b: &[u8]| {
+        if little {
+            LittleEndian::read_u32(b)
+        } else {
+            BigEndian::read_u32(b)
+        }
+    };
+    let ifd = read_u32(tiff.get(4..8)?) as usize;
+    let count = read_u16(tiff.get(ifd..ifd + 2)?) as usize;
+    (0..count).find_map(|i| {
+        let entry = tiff.get(ifd + 2 + i * 12..ifd + 14 + i * 12)?;
+        if read_u16(&entry[0..2]) == EXIF_ORIENTATION {
+            Some((little, read_u16(&entry[8..10])))
+        } else {
+            None
+        }
+    })
+}
+
+// An EXIF block with nothing but the orientation tag
+fn orientation_exif(little: bool, orientation: u16) -> Vec<u8> {
+    let mut tiff = [0u8; 26];
+    let (write_u16, write_u32): (fn(&mut [u8], u16), fn(&mut [u8], u32)) = if little {
+        tiff[..2].copy_from_slice(b"II");
+        (LittleEndian::write_u16, LittleEndian::write_u32)
+    } else {
+        tiff[..2].copy_from_slice(b"MM");
+        (BigEndian::write_u16, BigEndian::write_u32)
+    };
+    write_u16(&mut tiff[2..4], 42);
+    // IFD0 directly after the header, with one entry
+    write_u32(&mut tiff[4..8], 8);
+    write_u16(&mut tiff[8..10], 1);
+    // Tag, type SHORT, count 1, value
+    write_u16(&mut tiff[10..12], EXIF_ORIENTATION);
+    write_u16(&mut tiff[12..14], 3);
+    write_u32(&mut tiff[14..18], 1);
+    write_u16(&mut tiff[18..20], orientation);
+    // No next IFD, tiff[22..26] is left as zero
+    let mut exif = b"Exif\0\0".to_vec();
+    exif.extend_from_slice(&tiff);
+    exif
+}
+
+fn strip_jpeg(content: &[u8]) -> Option<Vec<u8>> {
+    if content.get(..2)? != b"\xff\xd8" {
+        return None;
+    }
+    let mut res = content[..2].to_vec();
+    let mut pos = 2;
+    loop {
+        if *content.get(pos)? != 0xff {
+            return None;
+        }
+        let marker = *content.get(pos + 1)?;
+        // Markers may be padded with any number of 0xff bytes
+        if marker == 0xff {
+            pos += 1;
+            continue;
+        }
+        // Start of scan, everything after it is image data
+        if marker == 0xda || marker == 0xd9 {
+            res.extend_from_slice(&content[pos..]);
+            return Some(res);
+        }
+        let len = BigEndian::read_u16(content.get(pos + 2..pos + 4)?) as usize;
+        if len < 2 {
+            return None;
+        }
+        let segment = content.get(pos..pos + 2 + len)?;
+        let data = &segment[4..];
+        match marker {
+            // APP1 holds EXIF and XMP
+            0xe1 => {
+                if let Some((little, orientation)) = exif_orientation(data) {
+                    if orientation != 1 {
+                        let exif = orientation_exif(little, orientation);
+                        res.extend_from_slice(&[0xff, 0xe1]);
+                        res.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+                        res.extend_from_slice(&exif);
+                    }
+                }
+            }
+            // APP2 is kept only for ICC color profiles
+            0xe2 if data.starts_with(b"ICC_PROFILE\0") => res.extend_from_slice(segment),
+            // APP2-APP13 (APP13 is IPTC) and comments
+            0xe2..=0xed | 0xfe => (),
+            _ => res.extend_from_slice(segment),
+        }
+        pos += 2 + len;
+    }
+}
+
+fn strip_png(content: &[u8]) -> Option<Vec<u8>> {
+    if content.get(..8)? != b"\x89PNG\r\n\x1a\n" {
+        return None;
+    }
+    let mut res = content[..8].to_vec();
+    let mut pos = 8;
+    while pos < content.len() {
+        let len = BigEndian::read_u32(content.get(pos..pos + 4)?) as usize;
+        // Length, type, data and crc
+        let chunk = content.get(pos..pos + 12 + len)?;
+        match &chunk[4..8] {
+            // XMP lives in an iTXt chunk
+            b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME" => (),
+            _ => res.extend_from_slice(chunk),
+        }
+        pos += 12 + len;
+    }
+    Some(res)
+}
+
+// VP8X flags for the presence of EXIF and XMP chunks
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+
+fn strip_webp(content: &[u8]) -> Option<Vec<u8>> {
+    if content.get(..4)? != b"RIFF" || content.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let mut res = content[..12].to_vec();
+    let mut pos = 12;
+    while pos < content.len() {
+        let len = LittleEndian::read_u32(content.get(pos + 4..pos + 8)?) as usize;
+        // Chunks are padded to an even size
+        let end = (pos + 8 + len + (len & 1)).min(content.len());
+        let chunk = content.get(pos..end)?;
+        match &chunk[..4] {
+            b"EXIF" | b"XMP " => (),
+            b"VP8X" => {
+                let start = res.len();
+                res.extend_from_slice(chunk);
+                *res.get_mut(start + 8)? &= !(WEBP_EXIF_FLAG | WEBP_XMP_FLAG);
+            }
+            _ => res.extend_from_slice(chunk),
+        }
+        pos = end;
+    }
+    let riff_len = (res.len() - 8) as u32;
+    LittleEndian::write_u32(&mut res[4..8], riff_len);
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jpeg_segment(marker: u8, data: &[u8]) -> Vec<u8> {
+        let mut res = vec![0xff, marker];
+        res.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+        res.extend_from_slice(data);
+        res
+    }
+
+    fn png_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut res = (data.len() as u32).to_be_bytes().to_vec();
+        res.extend_from_slice(kind);
+        res.extend_from_slice(data);
+        // The crc isn't checked
+        res.extend_from_slice(&[0; 4]);
+        res
+    }
+
+    fn webp_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut res = kind.to_vec();
+        res.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        res.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            res.push(0);
+        }
+        res
+    }
+
+    #[test]
+    fn orientation() {
+        for &little in &[true, false] {
+            let exif = orientation_exif(little, 6);
+            assert_eq!(exif_orientation(&exif), Some((little, 6)));
+        }
+        assert_eq!(exif_orientation(b"http://ns.adobe.com/xap/1.0/\0"), None);
+        assert_eq!(exif_orientation(b"Exif\0\0II*\0"), None);
+    }
+
+    #[test]
+    fn jpeg() {
+        let quant = jpeg_segment(0xdb, &[0; 65]);
+        let icc = jpeg_segment(0xe2, b"ICC_PROFILE\0\x01\x01");
+        let scan = [0xff, 0xda, 0x00, 0x02, 1, 2, 3, 0xff, 0xd9];
+        let image = |segments: &[Vec<u8>]| {
+            let mut res = vec![0xff, 0xd8];
+            res.extend(segments.concat());
+            res.extend_from_slice(&scan);
+            res
+        };
+        let content = image(&[
+            jpeg_segment(0xe0, b"JFIF\0\x01\x01"),
+            jpeg_segment(0xe1, &orientation_exif(false, 1)),
+            jpeg_segment(0xe1, b"http://ns.adobe.com/xap/1.0/\0<x/>"),
+            icc.clone(),
+            jpeg_segment(0xed, b"Photoshop 3.0\0"),
+            jpeg_segment(0xfe, b"comment"),
+            quant.clone(),
+        ]);
+        assert_eq!(
+            strip("image/jpeg", &content),
+            Some(image(&[jpeg_segment(0xe0, b"JFIF\0\x01\x01"), icc, quant]))
+        );
+
+        // Only the orientation is kept from EXIF, if it isn't the default
+        let exif = orientation_exif(true, 8);
+        let mut full_exif = exif.clone();
+        full_exif.extend_from_slice(b"camera and location");
+        assert_eq!(
+            strip("image/jpeg", &image(&[jpeg_segment(0xe1, &full_exif)])),
+            Some(image(&[jpeg_segment(0xe1, &exif)]))
+        );
+
+        assert_eq!(strip("image/jpeg", b"\xff\xd8\xff\xe1\x00\x40"), None);
+    }
+
+    #[test]
+    fn png() {
+        let image = |chunks: &[&[u8; 4]]| {
+            let mut res = b"\x89PNG\r\n\x1a\n".to_vec();
+            for kind in chunks {
+                res.extend(png_chunk(&kind[..], b"data"));
+            }
+            res
+        };
+        assert_eq!(
+            strip(
+                "image/png",
+                &image(&[b"IHDR", b"tEXt", b"eXIf", b"iCCP", b"iTXt", b"IDAT", b"tIME", b"IEND"])
+            ),
+            Some(image(&[b"IHDR", b"iCCP", b"IDAT", b"IEND"]))
+        );
+        assert_eq!(
+            strip("image/png", b"\x89PNG\r\n\x1a\n\x00\x00\x00\x10IHDR"),
+            None
+        );
+    }
+
+    #[test]
+    fn webp() {
+        let image = |chunks: &[Vec<u8>]| {
+            let body = chunks.concat();
+            let mut res = b"RIFF".to_vec();
+            res.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+            res.extend_from_slice(b"WEBP");
+            res.extend(body);
+            res
+        };
+        let vp8x = |flags: u8| webp_chunk(b"VP8X", &[flags, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let data = webp_chunk(b"VP8L", b"pixels");
+        let content = image(&[
+            vp8x(0x20 | WEBP_EXIF_FLAG | WEBP_XMP_FLAG),
+            data.clone(),
+            webp_chunk(b"EXIF", b"odd"),
+            webp_chunk(b"XMP ", b"<x/>"),
+        ]);
+        assert_eq!(
+            strip("image/webp", &content),
+            Some(image(&[vp8x(0x20), data]))
+        );
+    }
+
+    #[test]
+    fn unsupported() {
+        assert!(false == is_supported("image/gif"));
+        assert_eq!(strip("image/gif", b"GIF89a"), None);
+        assert_eq!(strip("image/png", b"\xff\xd8\xff"), None);
+    }
+}
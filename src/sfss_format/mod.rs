@@ -1,4 +1,7 @@
+mod detect;
 mod fileflags;
 pub mod filetype;
+mod metadata;
 mod sfss_format;
+mod sniff;
 pub use sfss_format::*;
@@ -4,13 +4,33 @@ use std::io::ErrorKind as IoErrorKind;
 use std::io::Result as IoResult;
 use std::io::{BufReader, Cursor, Read, Seek, SeekFrom, Write};
 
-use crate::context::CodeContext;
+use crate::archive;
+use crate::bundle;
+use crate::context::{
+    ArchiveContext, BundleContext, CodeContext, EncryptedContext, LinksContext, MarkdownContext,
+    MediaContext,
+};
+use crate::encryption::{self, DataKey};
+use crate::highlight::highlight;
+use crate::markdown;
 use crate::panic_dbg;
+use crate::security;
+use crate::sfss_format::detect::detect_language;
 use crate::sfss_format::fileflags::FileFlags;
 use crate::sfss_format::filetype::{BinaryType, FileType};
-use crate::utils::{bools_to_u8, u8_to_bools};
+use crate::sfss_format::metadata;
+use crate::sfss_format::sniff::sniff;
+use crate::unfurl;
+use crate::utils::{bools_to_u8, human_size, percent_encode, u8_to_bools, unix_time};
+use crate::views;
 
 use byteorder::{ByteOrder, LE};
+
+// Codes are base62 hashes, only ever made of ascii letters and digits
+pub fn is_code(code: &str) -> bool {
+    false == code.is_empty() && code.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
 const MAGIC_BYTES: [u8; 6] = [53, 46, 53, 53, 253, 254];
 
 // FILE STRUCTURE:
@@ -20,6 +40,28 @@ const MAGIC_BYTES: [u8; 6] = [53, 46, 53, 53, 253, 254];
 // 4 bytes: FILETYPE [MAJOR_TYPE IDENTIFIER IDENTIFIER IDENTIFIER]
 // 8 bytes: PASSWORD
 // 1 bytes: FLAGS
+// If the EXTENDED flag (last bit) is set, a list of fields follows:
+// 1 bytes: FIELD TAG (0 ends the list)
+// 2 bytes: FIELD LEN
+// X bytes: FIELD DATA
+// Unknown field tags are skipped when reading, so new fields can be added
+// without breaking older files. Versions of sfss from before the fields don't
+// know the flag and read them as part of the body, so they can't read files
+// that have any
+
+const FIELD_END: u8 = 0;
+const FIELD_MIME: u8 = 1;
+const FIELD_PARENT: u8 = 2;
+const FIELD_MAX_VIEWS: u8 = 3;
+const FIELD_VIEWS: u8 = 4;
+// The data key the body is encrypted with, wrapped with the master key
+const FIELD_DATA_KEY: u8 = 5;
+// The data key wrapped with a key derived from the password, for protected files
+const FIELD_PASSWORD_KEY: u8 = 6;
+// Who uploaded the file, like key:NAME for uploads with an API key
+const FIELD_OWNER: u8 = 7;
+// When the file is deleted, in seconds since the unix epoch
+const FIELD_EXPIRES: u8 = 8;
 
 #[derive(PartialEq, Eq)]
 pub struct SfssFile {
@@ -28,8 +70,24 @@ pub struct SfssFile {
     pub filetype: FileType,
     pub flags: FileFlags,
     pub password: Option<String>,
+    pub mime: Option<String>,
+    // The file this one is a revision of
+    pub parent: Option<String>,
+    // The file is deleted once it has been viewed this many times
+    pub max_views: Option<u32>,
+    pub views: u32,
+    pub owner: Option<String>,
+    pub expires: Option<u64>,
     pub file: std::path::PathBuf,
     pub compressed: bool,
+    // Set for files that are encrypted at rest, the data key is only unwrapped
+    // when the body is read
+    wrapped_key: Option<Vec<u8>>,
+    // Set for protected files, which can only be read after unlocking them with the password
+    password_key: Option<Vec<u8>>,
+    data_key: Option<DataKey>,
+    // Mixed into the hash of uploads that must not share the file with the same content
+    salt: Option<[u8; 16]>,
     buf: Vec<u8>,
 }
 
@@ -43,6 +101,11 @@ impl std::fmt::Debug for SfssFile {
 	Type: {:?}
 	Flags: {:?}
 	Password: {:?}
+	Mime: {:?}
+	Parent: {:?}
+	Views: {:?}/{:?}
+	Owner: {:?}
+	Expires: {:?}
 	Path: {:?}
 	Compressed {:?}
 }}"#,
@@ -51,6 +114,12 @@ impl std::fmt::Debug for SfssFile {
             self.filetype,
             self.flags,
             self.password,
+            self.mime,
+            self.parent,
+            self.views,
+            self.max_views,
+            self.owner,
+            self.expires,
             self.file,
             self.compressed
         )
@@ -62,11 +131,21 @@ impl std::default::Default for SfssFile {
         Self {
             filename: String::default(),
             hash: String::default(),
-            filetype: FileType::Text, // TODO: Change to check magic bytes of input
+            filetype: FileType::Text,
             file: std::path::PathBuf::from(std::env::var("SFSS_LOCATION").unwrap()),
             flags: FileFlags::default(),
             password: None,
+            mime: None,
+            parent: None,
+            max_views: None,
+            views: 0,
+            owner: None,
+            expires: None,
             compressed: false,
+            wrapped_key: None,
+            password_key: None,
+            data_key: None,
+            salt: None,
             buf: Vec::new(),
         }
     }
@@ -74,19 +153,158 @@ impl std::default::Default for SfssFile {
 
 use rocket::http::ContentType;
 impl SfssFile {
-    fn content_type(&self) -> ContentType {
+    fn content_type(&self, raw: bool) -> ContentType {
         match self.filetype {
             FileType::Text => ContentType::Plain,
-            FileType::Code(_) => ContentType::HTML,
-            FileType::Binary(BinaryType::Previewable) => {
-                ContentType::from_extension(self.filename.rsplit('.').nth(0).unwrap())
-                    .unwrap_or(ContentType::Binary)
-            }
+            FileType::Code(_) | FileType::Markdown | FileType::Bundle if raw => ContentType::Plain,
+            FileType::Code(_) | FileType::Markdown | FileType::Bundle => ContentType::HTML,
+            FileType::Binary(BinaryType::Previewable) => self
+                .mime
+                .as_ref()
+                .and_then(|mime| ContentType::parse_flexible(mime))
+                // Files uploaded before the mime type was stored in the header
+                .or_else(|| ContentType::from_extension(self.filename.rsplit('.').nth(0).unwrap()))
+                .unwrap_or(ContentType::Binary),
             FileType::Binary(BinaryType::NonPreviewable) => ContentType::Binary,
         }
     }
 
+    // Which kind of media page the file gets, only for types the browser is
+    // allowed to display inline
+    pub fn media_kind(&self) -> Option<&'static str> {
+        if self.filetype != FileType::Binary(BinaryType::Previewable) {
+            return None;
+        }
+        let content_type = self.content_type(true);
+        if false == security::is_inline_safe(&content_type) {
+            return None;
+        }
+        match content_type.top().as_str() {
+            "image" => Some("image"),
+            "video" => Some("video"),
+            "audio" => Some("audio"),
+            _ if content_type == ContentType::PDF => Some("pdf"),
+            _ => None,
+        }
+    }
+
+    // A new file with the same flags, password and parent, used when a single
+    // upload contains multiple files
+    fn sibling(&self, filename: String) -> Self {
+        let mut res = Self::create(filename, false, false, false);
+        res.flags = FileFlags {
+            public: self.flags.public,
+            protected: self.flags.protected,
+            no_preview: self.flags.no_preview,
+            encrypted: self.flags.encrypted,
+        };
+        res.password = self.password.clone();
+        res.parent = self.parent.clone();
+        res.max_views = self.max_views;
+        res.owner = self.owner.clone();
+        res.expires = self.expires;
+        res
+    }
+
+    // Removes EXIF and the like from images, this has to happen before the
+    // file is flushed since it changes the hash. Returns false if the image
+    // couldn't be read, it isn't kept then since it might still have metadata
+    fn strip_metadata(&mut self) -> bool {
+        if let Some(mime) = &self.mime {
+            match metadata::strip(mime, &self.buf) {
+                Some(stripped) => self.buf = stripped,
+                None if metadata::is_supported(mime) => return false,
+                None => (),
+            }
+        }
+        true
+    }
+
+    // Turns text into code or markdown, with either the chosen language or a detected one
+    fn set_language(&mut self, langid: Option<usize>, language_chosen: bool) {
+        if self.filetype != FileType::Text {
+            return;
+        }
+        let langid = if langid == None && false == language_chosen {
+            detect_language(&self.filename, &self.buf)
+        } else {
+            langid
+        };
+        if let Some(id) = langid {
+            self.filetype = if highlightjs_rs::from_id(id as usize) == Some("markdown") {
+                FileType::Markdown
+            } else {
+                FileType::Code(id as u32)
+            };
+        }
+    }
+
+    // Writes a new upload to disk. If a file with the same content already exists
+    // the two get merged, unless that would change the existing file for its
+    // uploader, then the upload is stored on its own under a code of its own
+    fn store(&mut self) -> IoResult<()> {
+        if let Err(err) = self.flush() {
+            if err.kind() != IoErrorKind::AlreadyExists {
+                return Err(err);
+            }
+            let existing = SfssFile::stored(self.hash.clone())?;
+            if false == self.can_merge(&existing) {
+                // Salted like the hash of protected files, so it can't collide again
+                self.salt = Some(rand::random());
+                self.write_new()?;
+                if let Some(owner) = &self.owner {
+                    crate::quota::added(owner, std::fs::metadata(&self.file)?.len());
+                }
+                return Ok(());
+            }
+            self.flags.public |= existing.flags.public;
+            self.flags.no_preview &= existing.flags.no_preview;
+            self.flags.encrypted |= existing.flags.encrypted;
+            // Uploading the same content again doesn't make it a new revision
+            self.parent = existing.parent;
+            self.hash = existing.hash;
+
+            self.force_write()?;
+        } else if let Some(owner) = &self.owner {
+            crate::quota::added(owner, std::fs::metadata(&self.file)?.len());
+        }
+        Ok(())
+    }
+
+    // Whether an upload can share the file that has the same content. Only if both
+    // have the same owner, who can delete it from the dashboard, and neither has
+    // a password, an expiry or a view limit the other would be stuck with
+    fn can_merge(&self, existing: &SfssFile) -> bool {
+        self.owner == existing.owner
+            && false == self.is_protected()
+            && false == existing.is_protected()
+            && self.expires.is_none()
+            && existing.expires.is_none()
+            && self.max_views.is_none()
+            && existing.max_views.is_none()
+    }
+
+    // A file that only lives in memory, like an entry of an archive, it has
+    // no hash and is never written to disk
+    pub fn transient(filename: String, content: Vec<u8>) -> Self {
+        let mut res = Self::create(filename, false, false, false);
+        res.buf = content;
+        res.sniff_type();
+        res.set_language(None, false);
+        res
+    }
+
+    // Sets the filetype and mime type based on the magic bytes of the content
+    fn sniff_type(&mut self) {
+        let (filetype, mime) = sniff(&self.buf);
+        self.filetype = filetype;
+        self.mime = Some(mime.to_string());
+    }
+
     fn force_write(&mut self) -> IoResult<()> {
+        let body = self.stored_body()?;
+        let old_size = std::fs::metadata(&self.file).map(|m| m.len()).ok();
+        let header = self.header_as_bytes();
         let mut fd = if self.file.is_file() {
             let mut fd = std::fs::OpenOptions::new().write(true).open(&self.file)?;
             fd.seek(SeekFrom::Start(0)).unwrap();
@@ -95,11 +313,168 @@ impl SfssFile {
         } else {
             std::fs::File::create(&self.file)?
         };
-        fd.write_all(&self.header_as_bytes())?;
-        fd.write_all(&self.buf)?;
+        fd.write_all(&header)?;
+        fd.write_all(&body)?;
+        self.resized(old_size, (header.len() + body.len()) as u64);
+        Ok(())
+    }
+
+    // Keeps the quota usage of the owner up to date when a file is written again
+    fn resized(&self, old_size: Option<u64>, new_size: u64) {
+        if let (Some(owner), Some(old_size)) = (&self.owner, old_size) {
+            crate::quota::resized(owner, old_size, new_size);
+        }
+    }
+
+    // Writes the file again with an updated header, through a temporary file so
+    // readers never see it half written. Only for files that are stored compressed
+    pub fn rewrite(&self) -> IoResult<()> {
+        self.replace_with(&self.stored_body()?)
+    }
+
+    fn replace_with(&self, body: &[u8]) -> IoResult<()> {
+        let old_size = std::fs::metadata(&self.file).map(|m| m.len()).ok();
+        let header = self.header_as_bytes();
+        let tmp = self.file.with_extension("tmp");
+        let mut fd = std::fs::File::create(&tmp)?;
+        fd.write_all(&header)?;
+        fd.write_all(body)?;
+        std::fs::rename(&tmp, &self.file)?;
+        self.resized(old_size, (header.len() + body.len()) as u64);
         Ok(())
     }
 
+    // Gives new files a data key, protected files always get one wrapped with
+    // their password, others only if encryption at rest is enabled
+    fn set_data_key(&mut self) -> IoResult<()> {
+        if self.data_key.is_some() {
+            return Ok(());
+        }
+        if let Some(password) = &self.password {
+            let data_key = encryption::new_data_key();
+            self.password_key = Some(encryption::wrap_with_password(&data_key, password)?);
+            self.data_key = Some(data_key);
+        } else if encryption::enabled() {
+            let data_key = encryption::new_data_key();
+            self.wrapped_key = Some(encryption::wrap(&data_key)?);
+            self.data_key = Some(data_key);
+        }
+        Ok(())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires.map_or(false, |expires| expires <= unix_time())
+    }
+
+    pub fn is_protected(&self) -> bool {
+        self.password.is_some() || self.password_key.is_some()
+    }
+
+    // Checks the password and decrypts the body with it, if the body was loaded.
+    // Files without a password open with anything
+    pub fn unlock(&mut self, password: Option<&str>) -> bool {
+        let wrapped = match &self.password_key {
+            Some(wrapped) => wrapped,
+            // Older files still have the password itself in the header
+            None => return self.password.is_none() || self.password.as_deref() == password,
+        };
+        let data_key = match password.and_then(|p| encryption::unwrap_with_password(wrapped, p)) {
+            Some(data_key) => data_key,
+            None => return false,
+        };
+        // Already unlocked or just uploaded, the body is decrypted already
+        if let Some(current) = &self.data_key {
+            return current == &data_key;
+        }
+        // The body is never empty once encrypted, it's empty if only the header was read
+        if false == self.buf.is_empty() {
+            match encryption::decrypt_body(&data_key, &self.hash, &self.buf) {
+                Ok(buf) => self.buf = buf,
+                Err(e) => {
+                    eprintln!("Error decrypting file {}: {:?}", self.hash, e);
+                    return false;
+                }
+            }
+        }
+        self.data_key = Some(data_key);
+        self.password = password.map(String::from);
+        true
+    }
+
+    // The key the body is encrypted with, None for files that aren't encrypted.
+    // Protected files have to be unlocked first
+    pub fn data_key(&self) -> IoResult<Option<DataKey>> {
+        if let Some(data_key) = self.data_key {
+            return Ok(Some(data_key));
+        }
+        if self.is_locked() {
+            return Err(IoError::from(IoErrorKind::PermissionDenied));
+        }
+        self.wrapped_key
+            .as_deref()
+            .map(encryption::unwrap)
+            .transpose()
+    }
+
+    // Protected files that haven't been unlocked still hold the encrypted body
+    fn is_locked(&self) -> bool {
+        self.password_key.is_some() && self.data_key.is_none()
+    }
+
+    // The compressed content as it is written to disk
+    fn stored_body(&self) -> IoResult<Vec<u8>> {
+        match &self.data_key {
+            Some(data_key) => encryption::encrypt_body(data_key, &self.hash, &self.buf),
+            None => Ok(self.buf.clone()),
+        }
+    }
+
+    // Decrypts the body right after reading it, if the file is encrypted at rest
+    fn decrypt_body(&mut self) -> IoResult<()> {
+        if let Some(wrapped) = &self.wrapped_key {
+            let data_key = encryption::unwrap(wrapped)?;
+            self.buf = encryption::decrypt_body(&data_key, &self.hash, &self.buf)?;
+            self.data_key = Some(data_key);
+        }
+        Ok(())
+    }
+
+    // Wraps the data key of a stored file with the current master key again.
+    // The body is copied as is, since the data key itself doesn't change
+    pub fn rewrap_key(hashcode: String) -> IoResult<bool> {
+        let mut file = Self::default();
+        file.file.push(&hashcode);
+        file.hash = hashcode;
+        let mut reader = BufReader::new(File::open(&file.file)?);
+        file.header_from_bytes(&mut reader)?;
+        let wrapped = match &file.wrapped_key {
+            Some(wrapped) if false == encryption::is_current(wrapped) => wrapped,
+            _ => return Ok(false),
+        };
+        file.wrapped_key = Some(encryption::wrap(&encryption::unwrap(wrapped)?)?);
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body)?;
+        drop(reader);
+        file.replace_with(&body)?;
+        Ok(true)
+    }
+
+    // Deletes the file along with its cached thumbnails
+    pub fn remove(&self) -> IoResult<()> {
+        let size = std::fs::metadata(&self.file)?.len();
+        std::fs::remove_file(&self.file)?;
+        if let Some(owner) = &self.owner {
+            crate::quota::removed(owner, size);
+        }
+        crate::thumbnail::remove_cached(self);
+        Ok(())
+    }
+
+    // The content of the file, only meaningful after it has been decompressed
+    pub fn content(&self) -> &[u8] {
+        &self.buf
+    }
+
     #[inline]
     pub fn verify_magic(bytes: [u8; 6]) -> bool {
         bytes == MAGIC_BYTES
@@ -107,10 +482,7 @@ impl SfssFile {
 
     pub fn set_password(&mut self) -> bool {
         if self.password == None {
-            self.password = passwords::PasswordGenerator::new()
-                .uppercase_letters(true)
-                .generate_one()
-                .ok();
+            self.password = crate::password::generate();
             true
         } else {
             false
@@ -123,10 +495,30 @@ impl SfssFile {
         self.header_from_bytes(&mut reader)?;
         self.compressed = true;
         reader.read_to_end(&mut self.buf)?;
-        Ok(())
+        self.decrypt_body()
     }
 
+    // Expired files are gone as far as anyone reading them is concerned, they are
+    // only deleted by the expiry sweep
     pub fn new(hashcode: String, only_header: bool) -> IoResult<Self> {
+        let res = Self::load(hashcode, only_header)?;
+        if res.is_expired() {
+            return Err(IoError::new(IoErrorKind::NotFound, "file expired"));
+        }
+        Ok(res)
+    }
+
+    // Reads the header of any stored file, even an expired one, for going through
+    // everything that is stored
+    pub fn stored(hashcode: String) -> IoResult<Self> {
+        Self::load(hashcode, true)
+    }
+
+    fn load(hashcode: String, only_header: bool) -> IoResult<Self> {
+        // Anything else could point outside of SFSS_LOCATION
+        if false == is_code(&hashcode) {
+            return Err(IoError::new(IoErrorKind::NotFound, "not a file code"));
+        }
         let mut path = std::path::PathBuf::from(std::env::var("SFSS_LOCATION").unwrap());
         let mut res = Self::default();
         path.push(&hashcode);
@@ -138,6 +530,7 @@ impl SfssFile {
         res.compressed = true;
         if false == only_header {
             reader.read_to_end(&mut res.buf)?;
+            res.decrypt_body()?;
         }
         Ok(res)
     }
@@ -146,22 +539,30 @@ impl SfssFile {
         SfssFile {
             filename,
             hash: String::default(),
-            filetype: FileType::Text, // TODO: Change to check magic bytes of input
+            filetype: FileType::Text,
             file: std::path::PathBuf::from(std::env::var("SFSS_LOCATION").unwrap()),
             flags: FileFlags {
                 public,
                 protected,
                 no_preview,
+                encrypted: false,
             },
             password: if protected {
-                passwords::PasswordGenerator::new()
-                    .uppercase_letters(true)
-                    .generate_one()
-                    .ok()
+                crate::password::generate()
             } else {
                 None
             },
+            mime: None,
+            parent: None,
+            max_views: None,
+            views: 0,
+            owner: None,
+            expires: None,
             compressed: false,
+            wrapped_key: None,
+            password_key: None,
+            data_key: None,
+            salt: None,
             buf: Vec::new(),
         }
     }
@@ -173,25 +574,78 @@ impl SfssFile {
             .unwrap();
         buf.write_all(&self.filename.as_bytes()).unwrap();
         buf.write_all(&self.filetype.as_bytes()).unwrap();
-        if let Some(password) = &self.password {
-            buf.write_all(&password.as_bytes()).unwrap();
-        } else {
-            buf.write_all(b"\x00\x00\x00\x00\x00\x00\x00\x00").unwrap()
+        // Only older files have their password here, it was always 8 bytes long.
+        // Files encrypted with their password never store it
+        match (&self.password, &self.password_key) {
+            (Some(password), None) if password.len() == 8 => {
+                buf.write_all(&password.as_bytes()).unwrap()
+            }
+            _ => buf.write_all(b"\x00\x00\x00\x00\x00\x00\x00\x00").unwrap(),
         }
+        let fields = self.header_fields();
         let flags = [
             self.flags.public,
             self.flags.protected,
             self.flags.no_preview,
+            self.flags.encrypted,
             false,
             false,
             false,
-            false,
-            false,
+            false == fields.is_empty(),
         ];
         buf.write(&mut [bools_to_u8(flags)]).unwrap();
+        if false == fields.is_empty() {
+            for (tag, data) in fields {
+                buf.write_all(&[tag]).unwrap();
+                buf.write_all(&(data.len() as u16).to_le_bytes()).unwrap();
+                buf.write_all(&data).unwrap();
+            }
+            buf.write_all(&[FIELD_END]).unwrap();
+        }
         buf
     }
 
+    fn header_fields(&self) -> Vec<(u8, Vec<u8>)> {
+        let mut fields = Vec::new();
+        if let Some(mime) = &self.mime {
+            fields.push((FIELD_MIME, mime.as_bytes().to_vec()));
+        }
+        if let Some(parent) = &self.parent {
+            fields.push((FIELD_PARENT, parent.as_bytes().to_vec()));
+        }
+        if let Some(max_views) = self.max_views {
+            fields.push((FIELD_MAX_VIEWS, max_views.to_le_bytes().to_vec()));
+            fields.push((FIELD_VIEWS, self.views.to_le_bytes().to_vec()));
+        }
+        if let Some(wrapped) = &self.wrapped_key {
+            fields.push((FIELD_DATA_KEY, wrapped.clone()));
+        }
+        if let Some(wrapped) = &self.password_key {
+            fields.push((FIELD_PASSWORD_KEY, wrapped.clone()));
+        }
+        if let Some(owner) = &self.owner {
+            fields.push((FIELD_OWNER, owner.as_bytes().to_vec()));
+        }
+        if let Some(expires) = self.expires {
+            fields.push((FIELD_EXPIRES, expires.to_le_bytes().to_vec()));
+        }
+        fields
+    }
+
+    fn set_header_field(&mut self, tag: u8, data: Vec<u8>) {
+        match tag {
+            FIELD_MIME => self.mime = String::from_utf8(data).ok(),
+            FIELD_PARENT => self.parent = String::from_utf8(data).ok(),
+            FIELD_MAX_VIEWS if data.len() == 4 => self.max_views = Some(LE::read_u32(&data)),
+            FIELD_VIEWS if data.len() == 4 => self.views = LE::read_u32(&data),
+            FIELD_DATA_KEY => self.wrapped_key = Some(data),
+            FIELD_PASSWORD_KEY => self.password_key = Some(data),
+            FIELD_OWNER => self.owner = String::from_utf8(data).ok(),
+            FIELD_EXPIRES if data.len() == 8 => self.expires = Some(LE::read_u64(&data)),
+            _ => (),
+        }
+    }
+
     fn header_from_bytes<R: Read>(&mut self, reader: &mut BufReader<R>) -> IoResult<()> {
         let mut magic: [u8; 6] = [0; 6];
         reader.read_exact(&mut magic)?;
@@ -221,7 +675,23 @@ impl SfssFile {
 
         let mut flag_bytes: [u8; 1] = [0];
         reader.read(&mut flag_bytes)?;
-        self.flags = FileFlags::from_iter(&mut u8_to_bools(flag_bytes[0]).iter().copied());
+        let flags = u8_to_bools(flag_bytes[0]);
+        self.flags = FileFlags::from_iter(&mut flags.iter().copied());
+
+        if flags[7] {
+            loop {
+                let mut tag: [u8; 1] = [0];
+                reader.read_exact(&mut tag)?;
+                if tag[0] == FIELD_END {
+                    break;
+                }
+                let mut field_len: [u8; 2] = [0; 2];
+                reader.read_exact(&mut field_len)?;
+                let mut data = vec![0; LE::read_u16(&field_len) as usize];
+                reader.read_exact(&mut data)?;
+                self.set_header_field(tag[0], data);
+            }
+        }
 
         Ok(())
     }
@@ -256,20 +726,13 @@ impl SfssFile {
         Ok(size)
     }
 
-    fn hash(&self) -> String {
-        base_62::encode(&xxhash_rust::xxh3::xxh3_64(&self.buf).to_le_bytes())
-    }
-}
-
-impl Write for SfssFile {
-    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-        self.buf.write(buf)
-    }
-
-    fn flush(&mut self) -> IoResult<()> {
-        self.compress()?;
+    // Writes the compressed file under the code its content hashes to, fails with
+    // AlreadyExists if a file with that code is already stored
+    fn write_new(&mut self) -> IoResult<()> {
         self.hash = self.hash();
+        self.file = std::path::PathBuf::from(std::env::var("SFSS_LOCATION").unwrap());
         self.file.push(&self.hash);
+        let body = self.stored_body()?;
         let mut fd = if self.file.is_file() {
             let mut fd = std::fs::File::open(&self.file)?;
             let mut tmp_buf: [u8; 6] = [0; 6];
@@ -288,9 +751,34 @@ impl Write for SfssFile {
             std::fs::File::create(&self.file)?
         };
         fd.write_all(&self.header_as_bytes())?;
-        fd.write_all(&self.buf)?;
+        fd.write_all(&body)?;
         Ok(())
     }
+
+    fn hash(&self) -> String {
+        let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+        hasher.update(&self.buf);
+        // Protected files are unique, so their code tells nothing about the content
+        if let Some(wrapped) = &self.password_key {
+            hasher.update(encryption::password_salt(wrapped));
+        }
+        if let Some(salt) = &self.salt {
+            hasher.update(salt);
+        }
+        base_62::encode(&hasher.digest().to_le_bytes())
+    }
+}
+
+impl Write for SfssFile {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.buf.write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.compress()?;
+        self.set_data_key()?;
+        self.write_new()
+    }
 }
 
 impl Read for SfssFile {
@@ -312,47 +800,238 @@ use rocket::{
 
 impl<'r> Responder<'r, 'static> for SfssFile {
     fn respond_to(mut self, req: &'r Request<'_>) -> responseResult<'static> {
-        self.decompress().unwrap();
+        if self.is_locked() {
+            return Response::build().status(Status::Forbidden).ok();
+        }
+        if self.compressed {
+            self.decompress().unwrap();
+        }
+        // I would use path_segments().last but alas not working
+        let raw = req.uri().path().rsplit('/').next().unwrap() == "raw";
+        // Pages that link to other parts of the file would use up views of it, so
+        // files with a view limit are never shown as archives or media pages
+        let limited = self.max_views.is_some();
+        // Archives get a page listing their entries, unless previews are disabled
+        let archive = if raw || self.flags.no_preview || limited {
+            None
+        } else {
+            archive::kind(&self)
+        };
+        // Images, audio, video and PDFs get a page embedding the raw file. Files that
+        // aren't stored, like archive entries, have no raw file to embed
+        let media = if raw || self.flags.no_preview || self.hash.is_empty() || limited {
+            None
+        } else {
+            self.media_kind()
+        };
+        // Encrypted files get a page that fetches the raw file and decrypts it in the browser
+        let encrypted_page = false == raw && self.flags.encrypted && false == self.hash.is_empty();
+        // Code, markdown and bundles are turned into a html page, unless the raw file was requested
+        let rendered = false == raw
+            && (archive.is_some()
+                || encrypted_page
+                || media.is_some()
+                || matches!(
+                    self.filetype,
+                    FileType::Code(_) | FileType::Markdown | FileType::Bundle
+                ));
+
+        // Uploaded content is only served from the user content origin if one is set
+        if let Some(content_url) = &crate::APP_CONTEXT.content_url {
+            if false == rendered && false == security::is_content_host(req, content_url) {
+                let location = match req.uri().query() {
+                    Some(query) => format!("{}{}?{}", content_url, req.uri().path(), query),
+                    None => format!("{}{}", content_url, req.uri().path()),
+                };
+                return Response::build()
+                    .status(Status::SeeOther)
+                    .raw_header("Location", location)
+                    .ok();
+            }
+        }
+
+        // Link previews must not use up a view, so they get nothing at all. The page
+        // for encrypted files has none of the content, only fetching it is a view
+        let counts_view = limited && false == encrypted_page;
+        if counts_view && false == views::counts_as_view(req) {
+            return Response::build().status(Status::Forbidden).ok();
+        }
+
+        let mut content_type = if rendered {
+            ContentType::HTML
+        } else {
+            self.content_type(raw)
+        };
+        let mut inline = false == self.flags.no_preview;
+        // Never let the browser render something like html or svg on our origin
+        if false == rendered && false == security::is_inline_safe(&content_type) {
+            content_type = ContentType::Binary;
+            inline = false;
+        }
+        let csp = if encrypted_page {
+            security::encrypted_page_csp()
+        } else if media.is_some() {
+            security::media_page_csp()
+        } else {
+            security::csp_for(&content_type)
+        };
         let mut resp = Response::build();
-        resp.header(self.content_type())
-            .header(Header::new("Cache-Control", "max-age=31536000"))
+        resp.header(Header::new("Content-Security-Policy", csp))
+            .header(content_type)
+            .header(Header::new(
+                "Cache-Control",
+                // A cached copy would be a view that is never counted
+                if limited {
+                    "no-store".to_string()
+                } else {
+                    // Or outlive the file
+                    let max_age = self
+                        .expires
+                        .map_or(31536000, |expires| expires.saturating_sub(unix_time()));
+                    format!("max-age={}", max_age.min(31536000))
+                },
+            ))
             .header(Header::new(
                 "Content-Disposition",
                 format!(
                     "{}; filename=\"{}\"",
-                    if self.flags.no_preview {
-                        "attachment"
-                    } else {
-                        "inline"
-                    },
+                    if inline { "inline" } else { "attachment" },
                     self.filename
                 ),
             ));
-        // I would use path_segments().last but alas not working
-        if req.uri().path().rsplit('/').next().unwrap() != "raw" {
-            if let FileType::Code(id) = self.filetype {
-                use std::os::unix::net::UnixStream;
-                let lang = highlightjs_rs::from_id(id as usize).unwrap();
-                let content = String::from_utf8_lossy(&self.buf);
-                let mut stream = UnixStream::connect("/tmp/sfss/sfss.sock")
-                    .expect("HighlightJS server isnt running");
-                write!(stream, "{}:{}", lang, content).unwrap();
-                let mut response = String::new();
-                stream.read_to_string(&mut response).unwrap();
-                let ctx = CodeContext {
-                    hljsclass: lang,
-                    content: response,
-                };
-                return if let Ok(v) =
-                    handlebars::Handlebars::new().render_template(crate::sfss_templates::CODE, &ctx)
-                {
-                    resp.sized_body(v.len(), Cursor::new(v)).ok()
-                } else {
-                    Response::build().status(Status::InternalServerError).ok()
-                };
+        let link_password = if crate::unlock::in_query(req) {
+            self.password.as_deref().map(percent_encode)
+        } else {
+            None
+        };
+        let body = if rendered {
+            // Files that aren't stored, like archive entries, have nothing to link to,
+            // and following the links of a file with a view limit would use up views
+            let links = if self.hash.is_empty() || limited {
+                None
+            } else {
+                Some(LinksContext {
+                    code: self.hash.clone(),
+                    webroot: crate::APP_CONTEXT.webroot.clone(),
+                    password: link_password.clone(),
+                })
+            };
+            let meta = unfurl::meta(&self);
+            let mut hbs = handlebars::Handlebars::new();
+            if let Err(e) = hbs.register_partial("meta", crate::sfss_templates::META) {
+                eprintln!("Error registering meta template: {:?}", e);
+                return Response::build().status(Status::InternalServerError).ok();
+            }
+            let page = if encrypted_page {
+                let raw = format!("{}/{}/raw", crate::APP_CONTEXT.webroot, self.hash);
+                hbs.render_template(
+                    crate::sfss_templates::ENCRYPTED,
+                    &EncryptedContext {
+                        filename: self.filename.clone(),
+                        webroot: crate::APP_CONTEXT.webroot.clone(),
+                        raw: match &link_password {
+                            Some(password) => format!("{}?password={}", raw, password),
+                            None => raw,
+                        },
+                    },
+                )
+            } else if let Some(kind) = media {
+                hbs.render_template(
+                    crate::sfss_templates::MEDIA,
+                    &MediaContext {
+                        filename: self.filename.clone(),
+                        size: human_size(self.buf.len() as u64),
+                        mime: self.content_type(true).to_string(),
+                        kind,
+                        links,
+                        meta,
+                    },
+                )
+            } else if let Some(kind) = archive {
+                match archive::list(kind, &self.buf) {
+                    Ok(entries) => hbs.render_template(
+                        crate::sfss_templates::ARCHIVE,
+                        &ArchiveContext {
+                            filename: self.filename.clone(),
+                            entries,
+                            links,
+                            meta,
+                        },
+                    ),
+                    Err(e) => {
+                        eprintln!("Error listing archive {}: {:?}", self.hash, e);
+                        return Response::build().status(Status::InternalServerError).ok();
+                    }
+                }
+            } else {
+                match self.filetype {
+                    FileType::Code(id) => {
+                        let lang = highlightjs_rs::from_id(id as usize).unwrap();
+                        match highlight(lang, &String::from_utf8_lossy(&self.buf)) {
+                            Ok(content) => hbs.render_template(
+                                crate::sfss_templates::CODE,
+                                &CodeContext {
+                                    hljsclass: lang,
+                                    content,
+                                    links,
+                                    meta,
+                                },
+                            ),
+                            Err(e) => {
+                                eprintln!("Error highlighting file {}: {:?}", self.hash, e);
+                                return Response::build().status(Status::InternalServerError).ok();
+                            }
+                        }
+                    }
+                    FileType::Markdown => {
+                        let source = String::from_utf8_lossy(&self.buf).into_owned();
+                        hbs.render_template(
+                            crate::sfss_templates::MARKDOWN,
+                            &MarkdownContext {
+                                content: markdown::render(&source),
+                                source,
+                                links,
+                                meta,
+                            },
+                        )
+                    }
+                    FileType::Bundle => {
+                        let members = bundle::load_members(&self);
+                        let (images, files) = bundle::entries(&members, link_password.is_some());
+                        hbs.render_template(
+                            crate::sfss_templates::BUNDLE,
+                            &BundleContext {
+                                images,
+                                files,
+                                links,
+                                meta,
+                            },
+                        )
+                    }
+                    _ => unreachable!(),
+                }
+            };
+            match page {
+                Ok(v) => v.into_bytes(),
+                Err(_) => return Response::build().status(Status::InternalServerError).ok(),
+            }
+        } else {
+            std::mem::take(&mut self.buf)
+        };
+
+        // Only counted once the response is ready, so a failure to render it
+        // doesn't use up the view, or a file that is burnt after reading
+        if counts_view {
+            match views::record(&self) {
+                Ok(true) => (),
+                Ok(false) => return Response::build().status(Status::NotFound).ok(),
+                Err(e) => {
+                    eprintln!("Error counting view of file {}: {:?}", self.hash, e);
+                    return Response::build().status(Status::InternalServerError).ok();
+                }
             }
         }
-        resp.sized_body(self.buf.len(), Cursor::new(self.buf)).ok()
+        resp.sized_body(body.len(), Cursor::new(body)).ok()
     }
 }
 
@@ -360,38 +1039,150 @@ use multipart::server::Multipart;
 use rocket::data::{FromData, Outcome};
 use rocket::Data;
 
+// Why an upload was refused, sent back as the body of the response so the
+// uploader learns more than the status
+#[derive(Debug)]
+pub struct UploadError {
+    pub status: Status,
+    pub message: String,
+}
+
+impl From<Status> for UploadError {
+    fn from(status: Status) -> Self {
+        UploadError {
+            status,
+            message: status.to_string(),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for UploadError {
+    fn respond_to(self, _: &'r Request<'_>) -> responseResult<'static> {
+        Response::build()
+            .status(self.status)
+            .header(ContentType::Plain)
+            .sized_body(self.message.len(), Cursor::new(self.message))
+            .ok()
+    }
+}
+
+// A text field of the upload form, the error is kept if it isn't valid text
+fn read_field<R: Read>(data: &mut R, name: &str, error: &mut Option<String>) -> Option<String> {
+    let mut s = String::new();
+    match data.read_to_string(&mut s) {
+        Ok(_) => Some(s),
+        Err(_) => {
+            *error = Some(format!("the {} field isn't valid text", name));
+            None
+        }
+    }
+}
+
+fn refuse(status: Status, message: impl ToString) -> Outcome<SfssFile, UploadError> {
+    Outcome::Failure((
+        status,
+        UploadError {
+            status,
+            message: message.to_string(),
+        },
+    ))
+}
+
 #[rocket::async_trait]
 impl FromData for SfssFile {
-    type Error = IoError;
+    type Error = UploadError;
 
     async fn from_data(request: &Request<'_>, data: Data) -> Outcome<Self, Self::Error> {
-        // All of these errors should be reported
-        let ct = request
+        // Checked before reading the body, so nothing is read for unauthorized uploads
+        let user = crate::accounts::session_user(request);
+        let api_key = match crate::apikeys::from_request(request) {
+            Ok(None) if user.is_none() && false == crate::apikeys::anonymous_uploads() => {
+                return refuse(
+                    Status::Unauthorized,
+                    "uploads need an API key or an account",
+                )
+            }
+            Ok(api_key) => api_key,
+            Err(e) if e.kind() == IoErrorKind::PermissionDenied => {
+                return refuse(Status::Unauthorized, e)
+            }
+            Err(e) => {
+                eprintln!("Error reading API keys: {:?}", e);
+                return refuse(Status::InternalServerError, "error reading API keys");
+            }
+        };
+
+        let boundary = match request
             .headers()
             .get_one("Content-Type")
-            .expect("no content-type");
-        let idx = ct.find("boundary=").expect("no boundary");
-        let boundary = &ct[(idx + 9)..];
+            .and_then(|ct| ct.find("boundary=").map(|idx| &ct[(idx + 9)..]))
+        {
+            Some(boundary) => boundary,
+            None => {
+                return refuse(
+                    Status::BadRequest,
+                    "the upload has to be multipart/form-data",
+                )
+            }
+        };
 
         let mut d = Vec::new();
-        data.open(128usize * rocket::data::ByteUnit::MiB)
+        let limit = crate::quota::request_limit();
+        let read = match data
+            .open(rocket::data::ByteUnit::from(limit))
             .stream_to(&mut d)
             .await
-            .expect("Unable to read");
+        {
+            Ok(read) => read,
+            Err(e) => {
+                return refuse(
+                    Status::BadRequest,
+                    format!("error reading the upload: {}", e),
+                )
+            }
+        };
+        // The body is cut off at the limit, so reaching it means there was more
+        if read >= limit {
+            return refuse(Status::PayloadTooLarge, "the upload is too large");
+        }
 
         let mut mp = Multipart::with_body(Cursor::new(d), boundary);
+        // Holds the flags, password and parent shared by every uploaded file
         let mut sfss_file = SfssFile::create("".into(), false, false, false);
-        let mut written = false;
+        if let Some(api_key) = &api_key {
+            sfss_file.flags.public = api_key.public;
+            sfss_file.flags.no_preview = api_key.no_preview;
+            if api_key.protected {
+                sfss_file.flags.protected = true;
+                sfss_file.set_password();
+            }
+            sfss_file.owner = Some(api_key.owner());
+        } else if let Some(user) = &user {
+            sfss_file.owner = Some(crate::accounts::owner(user));
+        } else {
+            sfss_file.owner = crate::quota::anonymous_owner(crate::ratelimit::client_ip(request));
+        }
+        // Filename and content of every file part that isn't empty
+        let mut parts: Vec<(String, Vec<u8>)> = Vec::new();
+        let mut strip_metadata = crate::APP_CONTEXT.strip_metadata;
+        // Why a field was refused, like a chosen password, it can't be returned
+        // from inside foreach_entry
+        let mut field_error = None;
 
         use highlightjs_rs::{exact, to_id};
         let mut langid = None;
+        // Set once the uploader picked a language, even plaintext
+        let mut language_chosen = false;
 
         // Custom implementation parts
-        mp.foreach_entry(|mut entry| match &*entry.headers.name {
+        let res = mp.foreach_entry(|mut entry| match &*entry.headers.name {
             "language" => {
                 if entry.is_text() {
-                    let mut s = String::new();
-                    entry.data.read_to_string(&mut s).unwrap();
+                    let s = match read_field(&mut entry.data, "language", &mut field_error) {
+                        Some(s) => s,
+                        None => return,
+                    };
+                    language_chosen = s != "" && false == s.eq_ignore_ascii_case("auto");
                     if request.uri().segments().last() == Some("api") {
                         if s != "plaintext" {
                             langid = to_id(s.as_ref());
@@ -413,52 +1204,377 @@ impl FromData for SfssFile {
                 sfss_file.flags.protected = true;
                 sfss_file.set_password();
             }
+            "password" => {
+                let s = match read_field(&mut entry.data, "password", &mut field_error) {
+                    Some(s) => s,
+                    None => return,
+                };
+                // An empty field from the web form means a generated password, if protected
+                if false == s.is_empty() {
+                    match crate::password::check(&s) {
+                        Ok(()) => {
+                            sfss_file.flags.protected = true;
+                            sfss_file.password = Some(s);
+                        }
+                        Err(e) => field_error = Some(e),
+                    }
+                }
+            }
             "no_preview" => {
                 sfss_file.flags.no_preview = true;
             }
-            "file" => {
-                if false == written
-                    || (false == entry.is_text() && Some("".into()) != entry.headers.filename)
-                {
-                    if entry.is_text() {
-                        sfss_file.filetype = FileType::Text;
-                    } else {
-                        sfss_file.filetype = FileType::Binary(BinaryType::Previewable);
+            "keep_metadata" => {
+                strip_metadata = false;
+            }
+            "encrypted" => {
+                sfss_file.flags.encrypted = true;
+            }
+            "burn_after_read" => {
+                sfss_file.max_views = Some(1);
+            }
+            "expires" => {
+                let s = match read_field(&mut entry.data, "expires", &mut field_error) {
+                    Some(s) => s,
+                    None => return,
+                };
+                // In days, an empty field from the web form means never
+                if let Ok(days) = s.trim().parse::<u64>() {
+                    if days > 0 {
+                        sfss_file.expires =
+                            Some(unix_time().saturating_add(days.saturating_mul(24 * 60 * 60)));
+                    }
+                }
+            }
+            "max_views" => {
+                let s = match read_field(&mut entry.data, "max_views", &mut field_error) {
+                    Some(s) => s,
+                    None => return,
+                };
+                // An empty field from the web form means no limit
+                if let Ok(max_views) = s.trim().parse::<u32>() {
+                    if max_views > 0 && sfss_file.max_views.is_none() {
+                        sfss_file.max_views = Some(max_views);
                     }
-                    sfss_file.buf.clear();
-                    written = 0 != std::io::copy(&mut entry.data, &mut sfss_file).unwrap();
-                    sfss_file.filename = entry.headers.filename.unwrap_or("untitled.txt".into());
+                }
+            }
+            "parent" => {
+                let s = match read_field(&mut entry.data, "parent", &mut field_error) {
+                    Some(s) => s,
+                    None => return,
+                };
+                // Only link to files that actually exist
+                if is_code(&s) && SfssFile::new(s.clone(), true).is_ok() {
+                    sfss_file.parent = Some(s);
+                }
+            }
+            "file" => {
+                let mut content = Vec::new();
+                if let Err(e) = std::io::copy(&mut entry.data, &mut content) {
+                    field_error = Some(format!("error reading a file of the upload: {}", e));
+                    return;
+                }
+                // The web form always sends both the textarea and the file input
+                if false == content.is_empty() {
+                    let filename = entry
+                        .headers
+                        .filename
+                        .filter(|f| false == f.is_empty())
+                        .unwrap_or("untitled.txt".into());
+                    parts.push((filename, content));
                 }
             }
             _ => (),
-        })
-        .expect("Unable to iterate");
+        });
+        if let Err(e) = res {
+            return refuse(
+                Status::BadRequest,
+                format!("error reading the upload: {}", e),
+            );
+        }
 
-        if let Some(id) = langid {
-            if sfss_file.filetype == FileType::Text {
-                sfss_file.filetype = FileType::Code(id as u32);
+        if let Some(e) = field_error {
+            return refuse(Status::BadRequest, e);
+        }
+        if parts.is_empty() {
+            parts.push(("untitled.txt".into(), Vec::new()));
+        }
+        // A bundle page shows its members without counting views of them, and
+        // can't show encrypted members at all
+        if parts.len() > 1 && (sfss_file.max_views.is_some() || sfss_file.flags.encrypted) {
+            return refuse(
+                Status::BadRequest,
+                "view limits and encryption can't be used with multiple files",
+            );
+        }
+        let mut files = Vec::with_capacity(parts.len());
+        for (filename, content) in parts {
+            let max_size = api_key.as_ref().and_then(|api_key| api_key.max_size);
+            if max_size.map_or(false, |max_size| content.len() as u64 > max_size) {
+                return refuse(
+                    Status::PayloadTooLarge,
+                    format!("{} is larger than the API key allows", filename),
+                );
+            }
+            let mut member = sfss_file.sibling(filename);
+            member.buf = content;
+            if member.flags.encrypted {
+                // Nothing can be known about the content
+                member.filetype = FileType::Binary(BinaryType::NonPreviewable);
+                member.mime = Some("application/octet-stream".into());
+            } else {
+                member.sniff_type();
+                if strip_metadata && false == member.strip_metadata() {
+                    return refuse(
+                        Status::UnprocessableEntity,
+                        format!(
+                            "{} couldn't be read to remove its metadata",
+                            member.filename
+                        ),
+                    );
+                }
+                member.set_language(langid, language_chosen);
+            }
+            let mime = member.mime.as_deref().unwrap_or_default();
+            if crate::quota::max_size(mime)
+                .map_or(false, |max_size| member.buf.len() as u64 > max_size)
+            {
+                return refuse(
+                    Status::PayloadTooLarge,
+                    format!("{} is larger than {} files may be", member.filename, mime),
+                );
+            }
+            if api_key
+                .as_ref()
+                .map_or(false, |api_key| false == api_key.allows_type(mime))
+            {
+                return refuse(
+                    Status::UnsupportedMediaType,
+                    format!("{} files can't be uploaded with the API key", mime),
+                );
+            }
+            files.push(member);
+        }
+
+        // Held until everything is stored
+        let _reservation = match &sfss_file.owner {
+            Some(owner) => {
+                let limits = crate::quota::limits(owner, api_key.as_ref());
+                let bytes = files.iter().map(|member| member.buf.len() as u64).sum();
+                // The bundle is a file as well
+                let count = if files.len() > 1 { files.len() + 1 } else { 1 };
+                match crate::quota::reserve(owner, limits, bytes, count as u64) {
+                    Ok(reservation) => reservation,
+                    Err((status, e)) => return refuse(status, e),
+                }
             }
+            None => None,
         };
-        if let Err(err) = sfss_file.flush() {
-            if err.kind() == IoErrorKind::AlreadyExists {
-                let existing = SfssFile::new(sfss_file.hash.clone(), true).unwrap();
-                sfss_file.flags.public |= existing.flags.public;
-                if false == (existing.flags.protected && sfss_file.flags.protected) {
-                    sfss_file.flags.protected = false;
-                    sfss_file.password = None;
-                } else {
-                    sfss_file.password = existing.password;
-                };
-                sfss_file.flags.no_preview &= existing.flags.no_preview;
-                sfss_file.hash = existing.hash;
 
-                sfss_file.force_write().unwrap();
-            } else {
+        // Multiple files get uploaded as a bundle, which is what the parent refers to
+        if files.len() > 1 {
+            for member in files.iter_mut() {
+                member.parent = None;
+            }
+        }
+        for member in files.iter_mut() {
+            if let Err(err) = member.store() {
                 panic_dbg!(err);
             }
         }
+        let sfss_file = if files.len() == 1 {
+            files.pop().unwrap()
+        } else {
+            let mut bundle = sfss_file.sibling("bundle".into());
+            bundle.filetype = FileType::Bundle;
+            bundle.buf = bundle::manifest(&files);
+            if let Err(err) = bundle.store() {
+                panic_dbg!(err);
+            }
+            bundle
+        };
 
         // End custom
         Outcome::Success(sfss_file)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    lazy_static::lazy_static! {
+        // SFSS_LOCATION is read all over, so every test shares one directory
+        static ref LOCATION: tempdir::TempDir = {
+            let dir = tempdir::TempDir::new("sfss").unwrap();
+            std::env::set_var("SFSS_LOCATION", dir.path());
+            dir
+        };
+    }
+
+    // Tests write to the same directory, so each uses content of its own
+    fn upload(content: &[u8], owner: Option<&str>) -> SfssFile {
+        lazy_static::initialize(&LOCATION);
+        let mut file = SfssFile::create("test.txt".into(), true, false, false);
+        file.owner = owner.map(String::from);
+        file.write_all(content).unwrap();
+        file
+    }
+
+    #[test]
+    fn write_and_read_full() {
+        let content = b"File write and read test";
+        let mut input = upload(content, None);
+        assert_eq!(input.buf, content);
+        input.flush().unwrap();
+
+        let output = SfssFile::new(input.hash.clone(), false).unwrap();
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn file_compress_decompress() {
+        let content = b"File Compression and decompression test";
+        let mut input = upload(content, None);
+        input.flush().unwrap();
+        assert_ne!(input.buf, &content[..]);
+
+        input.decompress().unwrap();
+        assert_eq!(input.buf, &content[..]);
+    }
+
+    #[test]
+    fn compress_and_decompress() {
+        use flate2::read::ZlibDecoder;
+        use flate2::write::ZlibEncoder;
+        use flate2::Compression;
+
+        let content = "This is some plain text".as_bytes().to_vec();
+        let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+        e.write_all(&content).unwrap();
+        let compressed = e.finish().unwrap();
+
+        let mut z = ZlibDecoder::new(&compressed[..]);
+        let mut b = Vec::new();
+        z.read_to_end(&mut b).unwrap();
+
+        assert_eq!(content, b);
+    }
+
+    #[test]
+    fn header_fields() {
+        let mut input = upload(b"", Some("key:ci"));
+        input.mime = Some("text/plain".into());
+        input.parent = Some("abc".into());
+        input.max_views = Some(3);
+        input.views = 1;
+        input.expires = Some(42);
+
+        let mut output = SfssFile::default();
+        output
+            .header_from_bytes(&mut BufReader::new(&input.header_as_bytes()[..]))
+            .unwrap();
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        let mut input = upload(b"", None);
+        input.mime = Some("text/plain".into());
+        let mut bytes = input.header_as_bytes();
+        // A field from a later version, then the end of the list
+        assert_eq!(bytes.pop(), Some(FIELD_END));
+        bytes.extend_from_slice(&[99, 2, 0, b'h', b'i', FIELD_END]);
+        bytes.extend_from_slice(b"body");
+
+        let mut reader = BufReader::new(&bytes[..]);
+        let mut output = SfssFile::default();
+        output.header_from_bytes(&mut reader).unwrap();
+        assert_eq!(output.mime, input.mime);
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"body");
+    }
+
+    #[test]
+    fn protected_files() {
+        lazy_static::initialize(&LOCATION);
+        let content = b"Protected content";
+        let mut input = SfssFile::create("secret.txt".into(), true, true, false);
+        let password = input.password.clone().unwrap();
+        input.write_all(content).unwrap();
+        input.flush().unwrap();
+        // The code says nothing about the content
+        let mut public = upload(content, None);
+        public.flush().unwrap();
+        assert_ne!(input.hash, public.hash);
+
+        let mut output = SfssFile::new(input.hash.clone(), false).unwrap();
+        assert!(output.is_protected());
+        assert_eq!(output.password, None);
+        assert!(output.data_key().is_err());
+        assert!(false == output.unlock(None));
+        assert!(false == output.unlock(Some("wrong")));
+        assert!(output.unlock(Some(&password)));
+        // Once unlocked the password is still checked
+        assert!(false == output.unlock(Some("wrong")));
+        assert!(output.unlock(Some(&password)));
+        output.decompress().unwrap();
+        assert_eq!(output.content(), content);
+    }
+
+    #[test]
+    fn dedup_same_owner() {
+        let mut first = upload(b"Content uploaded twice", Some("user:a"));
+        first.store().unwrap();
+        let mut second = upload(b"Content uploaded twice", Some("user:a"));
+        second.store().unwrap();
+        assert_eq!(first.hash, second.hash);
+
+        // A view limit would change the file for the first upload as well
+        let mut limited = upload(b"Content uploaded twice", Some("user:a"));
+        limited.max_views = Some(1);
+        limited.store().unwrap();
+        assert_ne!(limited.hash, first.hash);
+        assert_eq!(
+            SfssFile::stored(first.hash.clone()).unwrap().max_views,
+            None
+        );
+    }
+
+    #[test]
+    fn no_dedup_across_owners() {
+        let mut first = upload(b"Content of two owners", Some("user:a"));
+        first.expires = Some(unix_time() + 60);
+        first.store().unwrap();
+        let mut second = upload(b"Content of two owners", None);
+        second.store().unwrap();
+        assert_ne!(first.hash, second.hash);
+
+        let existing = SfssFile::stored(first.hash.clone()).unwrap();
+        assert_eq!(existing.owner.as_deref(), Some("user:a"));
+        assert_eq!(existing.expires, first.expires);
+        let stored = SfssFile::stored(second.hash.clone()).unwrap();
+        assert_eq!(stored.owner, None);
+    }
+
+    #[test]
+    fn expired_files() {
+        let mut file = upload(b"Content that expired", None);
+        file.expires = Some(unix_time() - 1);
+        file.store().unwrap();
+        // Hidden from readers, but still there for the sweep
+        let err = SfssFile::new(file.hash.clone(), true).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::NotFound);
+        assert!(SfssFile::stored(file.hash.clone()).unwrap().is_expired());
+    }
+
+    #[test]
+    fn codes() {
+        assert!(is_code("aZ09"));
+        assert!(false == is_code(""));
+        assert!(false == is_code("../users.json"));
+        assert!(false == is_code("abc.webp"));
+        let err = SfssFile::stored("../users.json".into()).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,207 @@
+use crate::sfss_format::filetype::{BinaryType, FileType};
+
+pub const TEXT_MIME: &str = "text/plain; charset=utf-8";
+pub const BINARY_MIME: &str = "application/octet-stream";
+
+// (offset, magic bytes, mime type, previewable in the browser)
+const SIGNATURES: &[(usize, &[u8], &str, bool)] = &[
+    // Images
+    (0, b"\x89PNG\r\n\x1a\n", "image/png", true),
+    (0, b"\xff\xd8\xff", "image/jpeg", true),
+    (0, b"GIF87a", "image/gif", true),
+    (0, b"GIF89a", "image/gif", true),
+    (0, b"II*\x00", "image/tiff", false),
+    (0, b"MM\x00*", "image/tiff", false),
+    (0, b"8BPS", "image/vnd.adobe.photoshop", false),
+    // Documents
+    (0, b"%PDF-", "application/pdf", true),
+    // Audio
+    (0, b"fLaC", "audio/flac", true),
+    (0, b"OggS", "audio/ogg", true),
+    (0, b"MThd", "audio/midi", false),
+    // Video
+    (0, b"\x1a\x45\xdf\xa3", "video/webm", true),
+    (0, b"FLV\x01", "video/x-flv", false),
+    // Archives
+    (0, b"PK\x03\x04", "application/zip", false),
+    (0, b"PK\x05\x06", "application/zip", false),
+    (0, b"\x1f\x8b", "application/gzip", false),
+    (0, b"BZh", "application/x-bzip2", false),
+    (0, b"\xfd7zXZ\x00", "application/x-xz", false),
+    (0, b"\x28\xb5\x2f\xfd", "application/zstd", false),
+    (
+        0,
+        b"7z\xbc\xaf\x27\x1c",
+        "application/x-7z-compressed",
+        false,
+    ),
+    (0, b"Rar!\x1a\x07", "application/vnd.rar", false),
+    (257, b"ustar", "application/x-tar", false),
+    // Executables
+    (0, b"\x7fELF", "application/x-elf", false),
+    (0, b"\xfe\xed\xfa\xce", "application/x-mach-binary", false),
+    (0, b"\xfe\xed\xfa\xcf", "application/x-mach-binary", false),
+    (0, b"\xce\xfa\xed\xfe", "application/x-mach-binary", false),
+    (0, b"\xcf\xfa\xed\xfe", "application/x-mach-binary", false),
+    (0, b"\xca\xfe\xba\xbe", "application/java-vm", false),
+    (0, b"\x00asm", "application/wasm", false),
+];
+
+// Short signatures that plain text could start with as well, these are only
+// checked if the content isn't valid UTF-8
+const WEAK_SIGNATURES: &[(usize, &[u8], &str, bool)] = &[
+    (0, b"BM", "image/bmp", true),
+    (0, b"\x00\x00\x01\x00", "image/x-icon", true),
+    (0, b"ID3", "audio/mpeg", true),
+    (
+        0,
+        b"MZ",
+        "application/vnd.microsoft.portable-executable",
+        false,
+    ),
+];
+
+fn match_signature(
+    signatures: &[(usize, &[u8], &'static str, bool)],
+    content: &[u8],
+) -> Option<(&'static str, bool)> {
+    signatures
+        .iter()
+        .find(|(offset, magic, _, _)| content.get(*offset..*offset + magic.len()) == Some(*magic))
+        .map(|(_, _, mime, previewable)| (*mime, *previewable))
+}
+
+// Types that can't be matched by a fixed prefix alone. Like the weak
+// signatures these are only checked if the content isn't valid UTF-8, text
+// can have ftyp or RIFF in the right place just as well
+fn sniff_container(content: &[u8]) -> Option<(&'static str, bool)> {
+    let riff =
+        |kind: &[u8]| content.len() >= 12 && &content[..4] == b"RIFF" && &content[8..12] == kind;
+    if riff(b"WEBP") {
+        return Some(("image/webp", true));
+    }
+    if riff(b"WAVE") {
+        return Some(("audio/wav", true));
+    }
+    if riff(b"AVI ") {
+        return Some(("video/x-msvideo", false));
+    }
+    // ISO base media files, [size] ftyp [brand]
+    if content.len() >= 12 && &content[4..8] == b"ftyp" {
+        return match &content[8..12] {
+            b"avif" | b"avis" => Some(("image/avif", true)),
+            b"heic" | b"heix" | b"mif1" => Some(("image/heic", false)),
+            b"M4A " | b"M4B " => Some(("audio/mp4", true)),
+            b"qt  " => Some(("video/quicktime", false)),
+            _ => Some(("video/mp4", true)),
+        };
+    }
+    // MPEG audio frame without an ID3 tag
+    if content.len() >= 2 && content[0] == 0xff && content[1] & 0xe0 == 0xe0 {
+        return Some(("audio/mpeg", true));
+    }
+    None
+}
+
+fn is_text(content: &[u8]) -> bool {
+    false == content.contains(&0) && std::str::from_utf8(content).is_ok()
+}
+
+// Figures out the type of an upload from its content, instead of trusting
+// the content type that came with the multipart entry
+pub fn sniff(content: &[u8]) -> (FileType, &'static str) {
+    let text = is_text(content);
+    let found = match_signature(SIGNATURES, content).or_else(|| {
+        if text {
+            None
+        } else {
+            sniff_container(content).or_else(|| match_signature(WEAK_SIGNATURES, content))
+        }
+    });
+
+    match found {
+        Some((mime, true)) => (FileType::Binary(BinaryType::Previewable), mime),
+        Some((mime, false)) => (FileType::Binary(BinaryType::NonPreviewable), mime),
+        None if text => (FileType::Text, TEXT_MIME),
+        None => (FileType::Binary(BinaryType::NonPreviewable), BINARY_MIME),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PREVIEWABLE: FileType = FileType::Binary(BinaryType::Previewable);
+    const NON_PREVIEWABLE: FileType = FileType::Binary(BinaryType::NonPreviewable);
+
+    #[test]
+    fn signatures() {
+        assert_eq!(
+            sniff(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR"),
+            (PREVIEWABLE, "image/png")
+        );
+        assert_eq!(
+            sniff(b"PK\x03\x04\x14\x00"),
+            (NON_PREVIEWABLE, "application/zip")
+        );
+        // Strong signatures win even if the content is valid text
+        assert_eq!(sniff(b"%PDF-1.7\n"), (PREVIEWABLE, "application/pdf"));
+
+        let mut tar = vec![0; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(sniff(&tar), (NON_PREVIEWABLE, "application/x-tar"));
+    }
+
+    #[test]
+    fn containers() {
+        assert_eq!(
+            sniff(b"RIFF\x24\x00\x00\x00WEBPVP8 "),
+            (PREVIEWABLE, "image/webp")
+        );
+        assert_eq!(
+            sniff(b"\x00\x00\x00\x20ftypisom\x00\x00\x02\x00"),
+            (PREVIEWABLE, "video/mp4")
+        );
+        assert_eq!(
+            sniff(b"\x00\x00\x00\x1cftypheic\x00\x00\x00\x00"),
+            (NON_PREVIEWABLE, "image/heic")
+        );
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(sniff(b"Hello, world!\n"), (FileType::Text, TEXT_MIME));
+        assert_eq!(sniff("Grüße\n".as_bytes()), (FileType::Text, TEXT_MIME));
+        assert_eq!(sniff(b""), (FileType::Text, TEXT_MIME));
+        // Text that happens to look like a container or a weak signature
+        assert_eq!(sniff(b"abcdftypisom and more"), (FileType::Text, TEXT_MIME));
+        assert_eq!(
+            sniff(b"RIFF....WAVE is a format"),
+            (FileType::Text, TEXT_MIME)
+        );
+        assert_eq!(sniff(b"BMW is a car brand"), (FileType::Text, TEXT_MIME));
+        assert_eq!(sniff(b"MZ"), (FileType::Text, TEXT_MIME));
+    }
+
+    #[test]
+    fn weak_signatures() {
+        assert_eq!(sniff(b"BM\x00\x01\x02\x03"), (PREVIEWABLE, "image/bmp"));
+        assert_eq!(sniff(b"ID3\x04\x00\x00"), (PREVIEWABLE, "audio/mpeg"));
+        assert_eq!(
+            sniff(b"MZ\x90\x00\x03\x00"),
+            (
+                NON_PREVIEWABLE,
+                "application/vnd.microsoft.portable-executable"
+            )
+        );
+    }
+
+    #[test]
+    fn unknown_binary() {
+        assert_eq!(sniff(b"\x00\x01\x02\x03"), (NON_PREVIEWABLE, BINARY_MIME));
+        assert_eq!(
+            sniff(b"\xc3\x28 invalid utf-8"),
+            (NON_PREVIEWABLE, BINARY_MIME)
+        );
+    }
+}
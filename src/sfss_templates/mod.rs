@@ -5,7 +5,18 @@ pub static UPLOAD_PASSWORD: &'static str = include_base_str!("templates/upload_p
 pub static UPLOAD_API: &'static str = include_base_str!("templates/upload_api.hbs");
 pub static UPLOAD_API_PASSWORD: &'static str =
     include_base_str!("templates/upload_api_password.hbs");
+pub static ARCHIVE: &'static str = include_base_str!("templates/archive.hbs");
+pub static BUNDLE: &'static str = include_base_str!("templates/bundle.hbs");
 pub static CODE: &'static str = include_base_str!("templates/code.hbs");
+pub static DIFF: &'static str = include_base_str!("templates/diff.hbs");
+pub static ENCRYPTED: &'static str = include_base_str!("templates/encrypted.hbs");
+pub static HISTORY: &'static str = include_base_str!("templates/history.hbs");
+pub static META: &'static str = include_base_str!("templates/meta.hbs");
+pub static UNLOCK: &'static str = include_base_str!("templates/unlock.hbs");
+pub static LOGIN: &'static str = include_base_str!("templates/login.hbs");
+pub static DASHBOARD: &'static str = include_base_str!("templates/dashboard.hbs");
+pub static MEDIA: &'static str = include_base_str!("templates/media.hbs");
+pub static MARKDOWN: &'static str = include_base_str!("templates/markdown.hbs");
 
 pub fn get_template(api: bool, password: bool) -> &'static str {
     if api {
@@ -0,0 +1,153 @@
+use std::io::Cursor;
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+use std::path::PathBuf;
+
+use image::{DynamicImage, GenericImageView, ImageOutputFormat};
+use rocket::http::ContentType;
+
+use crate::encryption::{self, DataKey};
+use crate::sfss_format::SfssFile;
+
+pub const DEFAULT_WIDTH: u32 = 256;
+// Requested widths are rounded up to one of these, so there is only a
+// handful of variants per image that can end up in the cache
+const WIDTHS: &[u32] = &[64, 128, 256, 512, 1024, 2048];
+// Images with more pixels than this aren't decoded at all
+const MAX_PIXELS: u64 = 50_000_000;
+
+pub fn snap_width(requested: Option<u32>) -> u32 {
+    let requested = requested.unwrap_or(DEFAULT_WIDTH);
+    WIDTHS
+        .iter()
+        .copied()
+        .find(|w| *w >= requested)
+        .unwrap_or(WIDTHS[WIDTHS.len() - 1])
+}
+
+// Thumbnails are cached next to the original as HASH.WIDTH.EXT, or as
+// HASH.WIDTH.EXT.enc encrypted with the data key of the file if it has one
+const EXTS: &[&str] = &["jpg", "png"];
+
+fn cache_path(file: &SfssFile, width: u32, ext: &str, encrypted: bool) -> PathBuf {
+    file.file.with_file_name(format!(
+        "{}.{}.{}{}",
+        file.hash,
+        width,
+        ext,
+        if encrypted { ".enc" } else { "" }
+    ))
+}
+
+// What encrypted thumbnails are bound to, so they can't be swapped around
+fn cache_aad(file: &SfssFile, width: u32) -> String {
+    format!("{}.{}", file.hash, width)
+}
+
+fn cached(
+    file: &SfssFile,
+    width: u32,
+    data_key: Option<&DataKey>,
+) -> Option<(ContentType, Vec<u8>)> {
+    EXTS.iter().find_map(|ext| {
+        let content = std::fs::read(cache_path(file, width, ext, data_key.is_some())).ok()?;
+        let content = match data_key {
+            Some(data_key) => {
+                encryption::decrypt_body(data_key, &cache_aad(file, width), &content).ok()?
+            }
+            None => content,
+        };
+        ContentType::from_extension(ext).map(|content_type| (content_type, content))
+    })
+}
+
+fn remove_variants(file: &SfssFile, encrypted: bool) {
+    for width in WIDTHS {
+        for ext in EXTS {
+            // Most widths were never generated
+            std::fs::remove_file(cache_path(file, *width, ext, encrypted)).ok();
+        }
+    }
+}
+
+// Removes every cached thumbnail of the file, for when the file itself is deleted
+pub fn remove_cached(file: &SfssFile) {
+    remove_variants(file, false);
+    remove_variants(file, true);
+}
+
+fn image_error(e: image::ImageError) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, e)
+}
+
+fn decode(content: &[u8]) -> IoResult<DynamicImage> {
+    let (w, h) = image::io::Reader::new(Cursor::new(content))
+        .with_guessed_format()?
+        .into_dimensions()
+        .map_err(image_error)?;
+    if w as u64 * h as u64 > MAX_PIXELS {
+        return Err(IoError::new(IoErrorKind::InvalidData, "image too large"));
+    }
+    image::load_from_memory(content).map_err(image_error)
+}
+
+// Returns the thumbnail of the given width, generating and caching it on
+// the first request. Only the header of the file has to be loaded, the
+// content is only read from disk if the thumbnail isn't cached yet. Protected
+// files have to be unlocked already.
+// Thumbnails of encrypted files, protected or encrypted at rest, are cached
+// encrypted like the file, a thumbnail would give away the image. Only older
+// protected files without a data key aren't cached at all
+pub fn thumbnail(file: &SfssFile, width: u32) -> IoResult<(ContentType, Vec<u8>)> {
+    let data_key = file.data_key()?;
+    let cache = data_key.is_some() || false == file.is_protected();
+    if cache {
+        if let Some(thumbnail) = cached(file, width, data_key.as_ref()) {
+            return Ok(thumbnail);
+        }
+    }
+
+    let mut full = SfssFile::new(file.hash.clone(), false)?;
+    if false == full.unlock(file.password.as_deref()) {
+        return Err(IoError::from(IoErrorKind::PermissionDenied));
+    }
+    full.decompress()?;
+    let image = decode(full.content())?;
+    // Never scale images up
+    let image = if image.width() > width {
+        image.thumbnail(width, u32::MAX)
+    } else {
+        image
+    };
+
+    let (ext, format, content_type) = if image.color().has_alpha() {
+        ("png", ImageOutputFormat::Png, ContentType::PNG)
+    } else {
+        ("jpg", ImageOutputFormat::Jpeg(85), ContentType::JPEG)
+    };
+    let mut content = Vec::new();
+    image.write_to(&mut content, format).map_err(image_error)?;
+    if false == cache {
+        return Ok((content_type, content));
+    }
+
+    let stored = match &data_key {
+        Some(data_key) => {
+            // Plaintext ones from before the file was encrypted, like before
+            // encryption at rest was turned on
+            remove_variants(file, false);
+            encryption::encrypt_body(data_key, &cache_aad(file, width), &content)?
+        }
+        None => content.clone(),
+    };
+    // Written to a temporary file first, so a concurrent request never reads half a thumbnail
+    let path = cache_path(file, width, ext, data_key.is_some());
+    let tmp = path.with_file_name(format!(
+        "{}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    std::fs::write(&tmp, &stored)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok((content_type, content))
+}
@@ -0,0 +1,151 @@
+use std::io::Cursor;
+
+use serde::Serialize;
+
+use crate::context::MetaContext;
+use crate::sfss_format::filetype::FileType;
+use crate::sfss_format::SfssFile;
+use crate::thumbnail;
+use crate::utils::{human_size, percent_encode};
+
+// Link previews show at most this much of the start of a text file
+const DESCRIPTION_LINES: usize = 5;
+const DESCRIPTION_LEN: usize = 300;
+const IMAGE_WIDTH: u32 = 1024;
+
+fn page_url(code: &str) -> String {
+    format!(
+        "{}{}/{}",
+        crate::APP_CONTEXT.url,
+        crate::APP_CONTEXT.webroot,
+        code
+    )
+}
+
+fn description(file: &SfssFile) -> String {
+    match file.filetype {
+        FileType::Text | FileType::Code(_) | FileType::Markdown => {
+            let text = String::from_utf8_lossy(file.content());
+            let mut res = text
+                .lines()
+                .take(DESCRIPTION_LINES)
+                .collect::<Vec<_>>()
+                .join("\n");
+            if res.len() > DESCRIPTION_LEN {
+                let mut end = DESCRIPTION_LEN;
+                while false == res.is_char_boundary(end) {
+                    end -= 1;
+                }
+                res.truncate(end);
+                res.push_str("...");
+            }
+            res
+        }
+        FileType::Bundle => format!("{} files", crate::bundle::member_codes(file).len()),
+        FileType::Binary(_) => match &file.mime {
+            Some(mime) => format!("{}, {}", mime, human_size(file.content().len() as u64)),
+            None => human_size(file.content().len() as u64),
+        },
+    }
+}
+
+// Whether anything about the file may be shown in link previews
+pub fn is_private(file: &SfssFile) -> bool {
+    file.flags.protected
+        || file.password.is_some()
+        || file.max_views.is_some()
+        || file.flags.encrypted
+}
+
+// Tags for link previews. Protected and encrypted files, and files with a view
+// limit get none at all, so neither their name nor their content ends up in a
+// chat, even if the link has the password
+pub fn meta(file: &SfssFile) -> Option<MetaContext> {
+    if is_private(file) || file.hash.is_empty() {
+        return None;
+    }
+    let url = page_url(&file.hash);
+    let image = if file.flags.no_preview || file.media_kind() != Some("image") {
+        None
+    } else {
+        Some(format!("{}/thumb?w={}", url, IMAGE_WIDTH))
+    };
+    Some(MetaContext {
+        site: crate::APP_CONTEXT.title.clone(),
+        title: file.filename.clone(),
+        description: description(file),
+        image,
+        oembed: format!(
+            "{}{}/oembed?url={}",
+            crate::APP_CONTEXT.url,
+            crate::APP_CONTEXT.webroot,
+            percent_encode(&url)
+        ),
+        url,
+    })
+}
+
+#[derive(Serialize)]
+pub struct OEmbed {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub version: &'static str,
+    pub title: String,
+    pub provider_name: String,
+    pub provider_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_height: Option<u32>,
+}
+
+// The code of a file from one of its urls, on either the main or the content origin
+pub fn code_from_url(url: &str) -> Option<String> {
+    let origins = std::iter::once(&crate::APP_CONTEXT.url).chain(&crate::APP_CONTEXT.content_url);
+    let path = origins
+        .map(|origin| format!("{}{}/", origin, crate::APP_CONTEXT.webroot))
+        .find(|prefix| url.starts_with(prefix.as_str()))
+        .map(|prefix| &url[prefix.len()..])?;
+    let code = path
+        .split(|c: char| c == '/' || c == '?' || c == '#')
+        .next()?;
+    if false == crate::sfss_format::is_code(code) {
+        return None;
+    }
+    Some(code.to_string())
+}
+
+// Only needs the header of the file, the thumbnail is the only content used
+pub fn oembed(file: &SfssFile) -> OEmbed {
+    let mut res = OEmbed {
+        kind: "link",
+        version: "1.0",
+        title: file.filename.clone(),
+        provider_name: crate::APP_CONTEXT.title.clone(),
+        provider_url: format!("{}{}/", crate::APP_CONTEXT.url, crate::APP_CONTEXT.webroot),
+        thumbnail_url: None,
+        thumbnail_width: None,
+        thumbnail_height: None,
+    };
+    if file.flags.no_preview || file.media_kind() != Some("image") {
+        return res;
+    }
+    let width = thumbnail::snap_width(None);
+    let dimensions = thumbnail::thumbnail(file, width)
+        .ok()
+        .and_then(|(_, content)| {
+            image::io::Reader::new(Cursor::new(content))
+                .with_guessed_format()
+                .ok()?
+                .into_dimensions()
+                .ok()
+        });
+    if let Some((w, h)) = dimensions {
+        res.thumbnail_url = Some(format!("{}/thumb?w={}", page_url(&file.hash), width));
+        res.thumbnail_width = Some(w);
+        res.thumbnail_height = Some(h);
+    }
+    res
+}
@@ -0,0 +1,136 @@
+use std::net::IpAddr;
+
+use rocket::http::{Cookie, SameSite, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::content::Html;
+
+use crate::context::UnlockContext;
+use crate::encryption;
+use crate::utils::unix_time;
+
+// Protected files can be unlocked through a form, which sets a cookie that is
+// only sent along for the urls of that file. The cookie holds the password
+// sealed with the server's token key, together with when it expires, so it
+// can't be read, changed, kept around or used for other files. Unlocking a
+// bundle sets one for each of its members as well.
+// API clients can send the password in the X-Sfss-Password header, or as the
+// password of basic auth (curl -u :PASSWORD) instead of in the query
+const COOKIE: &str = "sfss_unlock";
+const HEADER: &str = "X-Sfss-Password";
+const DEFAULT_LIFETIME_MINUTES: u64 = 30;
+
+// The body of the unlock form
+#[derive(FromForm)]
+pub struct UnlockForm {
+    pub password: Option<String>,
+}
+
+lazy_static::lazy_static! {
+    // How long an unlocked file stays unlocked, in seconds
+    static ref LIFETIME: u64 = std::env::var("SFSS_UNLOCK_MINUTES")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_LIFETIME_MINUTES)
+        * 60;
+}
+
+#[derive(Responder)]
+pub enum Locked {
+    #[response(status = 403)]
+    Page(Html<String>),
+    Status(Status),
+}
+
+// Everything a request can carry a password in besides the query, and who sent it
+pub struct Credentials {
+    header: Option<String>,
+    token: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for Credentials {
+    type Error = ();
+
+    async fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let basic = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|auth| auth.strip_prefix("Basic "))
+            .and_then(|encoded| base64::decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| decoded.splitn(2, ':').nth(1).map(String::from));
+        let header = req.headers().get_one(HEADER).map(String::from).or(basic);
+        let token = req.cookies().get(COOKIE).map(|c| c.value().to_string());
+        Outcome::Success(Credentials {
+            header,
+            token,
+            ip: crate::ratelimit::client_ip(req),
+        })
+    }
+}
+
+impl Credentials {
+    // The password from the query, a header or the cookie set by unlocking the file
+    pub fn password(&self, query: Option<String>, code: &str) -> Option<String> {
+        query
+            .or_else(|| self.header.clone())
+            .or_else(|| self.token.as_deref().and_then(|token| open(code, token)))
+    }
+}
+
+// Whether the request has the password in its query. Only then is it put into
+// the links of the page, a password from the cookie or a header never ends up in urls
+pub fn in_query(req: &Request<'_>) -> bool {
+    req.uri().query().map_or(false, |query| {
+        query
+            .to_string()
+            .split('&')
+            .any(|pair| pair.starts_with("password="))
+    })
+}
+
+fn open(code: &str, token: &str) -> Option<String> {
+    let sealed = base64::decode_config(token, base64::URL_SAFE_NO_PAD).ok()?;
+    let plaintext = encryption::open_token(code.as_bytes(), &sealed).ok()?;
+    if plaintext.len() < 8 {
+        return None;
+    }
+    let mut expires = [0; 8];
+    expires.copy_from_slice(&plaintext[..8]);
+    if u64::from_le_bytes(expires) < unix_time() {
+        return None;
+    }
+    String::from_utf8(plaintext[8..].to_vec()).ok()
+}
+
+// The cookie has no expiry of its own, the one sealed in it is what counts
+pub fn cookie(code: &str, password: &str) -> std::io::Result<Cookie<'static>> {
+    let mut plaintext = (unix_time() + *LIFETIME).to_le_bytes().to_vec();
+    plaintext.extend_from_slice(password.as_bytes());
+    let sealed = encryption::seal_token(code.as_bytes(), &plaintext)?;
+    Ok(Cookie::build(
+        COOKIE,
+        base64::encode_config(&sealed, base64::URL_SAFE_NO_PAD),
+    )
+    .path(format!("{}/{}", crate::APP_CONTEXT.webroot, code))
+    .http_only(true)
+    .secure(crate::APP_CONTEXT.url.starts_with("https://"))
+    .same_site(SameSite::Strict)
+    .finish())
+}
+
+pub fn page(code: &str, wrong: bool) -> Locked {
+    let ctx = UnlockContext {
+        code: code.to_string(),
+        webroot: crate::APP_CONTEXT.webroot.clone(),
+        wrong,
+    };
+    match handlebars::Handlebars::new().render_template(crate::sfss_templates::UNLOCK, &ctx) {
+        Ok(v) => Locked::Page(Html(v)),
+        Err(e) => {
+            eprintln!("{:?}", e);
+            Locked::Status(Status::InternalServerError)
+        }
+    }
+}
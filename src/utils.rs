@@ -29,3 +29,77 @@ pub fn u8_to_bools(byte: u8) -> [bool; 8] {
     }
     res
 }
+
+// Encodes everything but unreserved characters, for putting values in urls
+pub fn percent_encode(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                res.push(b as char)
+            }
+            _ => res.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    res
+}
+
+// Path of a file in SFSS_LOCATION that isn't an upload, those always have an extension
+pub fn data_path(name: &str) -> std::path::PathBuf {
+    let mut path = std::path::PathBuf::from(std::env::var("SFSS_LOCATION").unwrap());
+    path.push(name);
+    path
+}
+
+// Reads a json file from SFSS_LOCATION, a missing file is the default value
+pub fn read_json<T: serde::de::DeserializeOwned + Default>(name: &str) -> std::io::Result<T> {
+    match std::fs::read(data_path(name)) {
+        Ok(json) => serde_json::from_slice(&json)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+// Writes a json file to SFSS_LOCATION, through a temporary file
+pub fn write_json<T: serde::Serialize>(name: &str, value: &T) -> std::io::Result<()> {
+    let json = serde_json::to_vec_pretty(value)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    let tmp = data_path(name).with_extension("tmp");
+    std::fs::write(&tmp, json)?;
+    std::fs::rename(&tmp, data_path(name))
+}
+
+// Seconds since the unix epoch
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// Durations for showing on pages, like 3 days, rounded down to the largest unit
+pub fn human_duration(secs: u64) -> String {
+    const UNITS: &[(u64, &str)] = &[(86400, "day"), (3600, "hour"), (60, "minute")];
+    for (unit, name) in UNITS {
+        if secs >= *unit {
+            let count = secs / unit;
+            return format!("{} {}{}", count, name, if count == 1 { "" } else { "s" });
+        }
+    }
+    "less than a minute".into()
+}
+
+// File sizes for showing on pages, like 1.5 MiB
+pub fn human_size(size: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
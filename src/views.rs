@@ -0,0 +1,82 @@
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Result as IoResult;
+use std::sync::Mutex;
+
+use rocket::http::Method;
+use rocket::Request;
+
+use crate::sfss_format::SfssFile;
+
+lazy_static::lazy_static! {
+    // Held while a view is counted, so two requests can't both see the last view left
+    static ref COUNTER: Mutex<()> = Mutex::new(());
+}
+
+// Bots that fetch links pasted into chats and social media to show a preview
+const LINK_PREVIEWERS: &[&str] = &[
+    "slackbot",
+    "slack-imgproxy",
+    "twitterbot",
+    "facebookexternalhit",
+    "facebot",
+    "discordbot",
+    "telegrambot",
+    "whatsapp",
+    "linkedinbot",
+    "skypeuripreview",
+    "microsoftpreview",
+    "mattermost",
+    "rocket.chat",
+    "zulip",
+    "iframely",
+    "embedly",
+    "redditbot",
+    "pinterest",
+    "googlebot",
+    "bingbot",
+    "applebot",
+];
+
+// Head requests and link previews never count as a view
+pub fn counts_as_view(req: &Request<'_>) -> bool {
+    if req.method() == Method::Head {
+        return false;
+    }
+    let user_agent = req
+        .headers()
+        .get_one("User-Agent")
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    false == LINK_PREVIEWERS.iter().any(|bot| user_agent.contains(bot))
+}
+
+// Counts a view of a file with a view limit, and deletes it once the limit is
+// reached. Returns false if the views were already used up by someone else
+pub fn record(file: &SfssFile) -> IoResult<bool> {
+    let _guard = COUNTER.lock().unwrap_or_else(|e| e.into_inner());
+    // Read again while holding the lock, the count in the given file may be stale
+    let mut current = match SfssFile::new(file.hash.clone(), false) {
+        Ok(current) => current,
+        Err(e) if e.kind() == IoErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let max_views = match current.max_views {
+        Some(max_views) => max_views,
+        None => return Ok(true),
+    };
+    if current.views >= max_views {
+        current.remove()?;
+        return Ok(false);
+    }
+    current.views += 1;
+    if current.views >= max_views {
+        eprintln!(
+            "File {} reached its view limit and was deleted",
+            current.hash
+        );
+        current.remove()?;
+    } else {
+        current.rewrite()?;
+    }
+    Ok(true)
+}